@@ -0,0 +1,12 @@
+-- Create api_tokens table
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id SERIAL PRIMARY KEY,
+    uuid UUID DEFAULT gen_random_uuid() NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE, -- SHA-256 of the raw token, the token itself is never stored
+    site_mask INTEGER NOT NULL, -- Controls which sites this token can act on
+    capability TEXT NOT NULL CHECK (capability IN ('read', 'write', 'admin')),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
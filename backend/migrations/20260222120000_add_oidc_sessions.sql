@@ -0,0 +1,16 @@
+-- Link authors to their identity provider account
+ALTER TABLE authors ADD COLUMN oidc_subject TEXT UNIQUE;
+
+-- Sessions are tokens tied to an author that expire
+ALTER TABLE api_tokens
+ADD COLUMN author_uuid UUID REFERENCES authors(uuid) ON DELETE CASCADE,
+ADD COLUMN expires_at TIMESTAMPTZ;
+
+-- Logins waiting on the identity provider to call us back
+CREATE TABLE IF NOT EXISTS oidc_logins (
+    state TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    pkce_verifier TEXT NOT NULL,
+    site_mask INTEGER NOT NULL, -- Site the login was started from
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,8 @@
+-- Create site_roles table
+CREATE TABLE IF NOT EXISTS site_roles (
+    id SERIAL PRIMARY KEY,
+    author_uuid UUID NOT NULL REFERENCES authors(uuid) ON DELETE CASCADE,
+    site_mask_bit INTEGER NOT NULL, -- The site this role is granted on
+    role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'author', 'contributor', 'viewer')),
+    UNIQUE (author_uuid, site_mask_bit)
+);
@@ -0,0 +1,70 @@
+-- Sites no longer need a bit, only the first 31 keep one for the legacy visibility_mask API
+ALTER TABLE sites ALTER COLUMN site_mask_bit DROP NOT NULL;
+
+-- Posts
+CREATE TABLE IF NOT EXISTS post_sites (
+    post_id INTEGER NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, site_id)
+);
+CREATE INDEX IF NOT EXISTS post_sites_site_id_idx ON post_sites (site_id);
+
+INSERT INTO post_sites (post_id, site_id)
+SELECT p.id, s.id FROM posts p JOIN sites s ON (p.visibility_mask & s.site_mask_bit) <> 0;
+
+ALTER TABLE posts DROP COLUMN visibility_mask;
+
+-- Tags
+CREATE TABLE IF NOT EXISTS tag_sites (
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    PRIMARY KEY (tag_uuid, site_id)
+);
+CREATE INDEX IF NOT EXISTS tag_sites_site_id_idx ON tag_sites (site_id);
+
+INSERT INTO tag_sites (tag_uuid, site_id)
+SELECT t.tag_uuid, s.id FROM tag_stats t JOIN sites s ON (t.visibility_mask & s.site_mask_bit) <> 0;
+
+ALTER TABLE tag_stats DROP COLUMN visibility_mask;
+
+-- Author socials
+CREATE TABLE IF NOT EXISTS author_social_sites (
+    social_id INTEGER NOT NULL REFERENCES author_socials(id) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    PRIMARY KEY (social_id, site_id)
+);
+
+INSERT INTO author_social_sites (social_id, site_id)
+SELECT a.id, s.id FROM author_socials a JOIN sites s ON (a.visibility_mask & s.site_mask_bit) <> 0;
+
+ALTER TABLE author_socials DROP COLUMN visibility_mask;
+
+-- API tokens and sessions
+CREATE TABLE IF NOT EXISTS api_token_sites (
+    token_id INTEGER NOT NULL REFERENCES api_tokens(id) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    PRIMARY KEY (token_id, site_id)
+);
+
+INSERT INTO api_token_sites (token_id, site_id)
+SELECT t.id, s.id FROM api_tokens t JOIN sites s ON (t.site_mask & s.site_mask_bit) <> 0;
+
+ALTER TABLE api_tokens DROP COLUMN site_mask;
+
+-- Site roles, which now go away with their site instead of waiting for the bit to be reused
+ALTER TABLE site_roles ADD COLUMN site_id INTEGER REFERENCES sites(id) ON DELETE CASCADE;
+
+UPDATE site_roles r SET site_id = s.id FROM sites s WHERE s.site_mask_bit = r.site_mask_bit;
+DELETE FROM site_roles WHERE site_id IS NULL;
+
+ALTER TABLE site_roles
+DROP COLUMN site_mask_bit,
+ALTER COLUMN site_id SET NOT NULL,
+ADD CONSTRAINT site_roles_author_uuid_site_id_key UNIQUE (author_uuid, site_id);
+
+-- Pending logins only last a few minutes, no need to carry them over
+DELETE FROM oidc_logins;
+
+ALTER TABLE oidc_logins
+DROP COLUMN site_mask,
+ADD COLUMN site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE;
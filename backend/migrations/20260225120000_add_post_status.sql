@@ -0,0 +1,15 @@
+-- Posts move through draft -> scheduled -> published -> archived
+ALTER TABLE posts
+ADD COLUMN status TEXT NOT NULL DEFAULT 'draft' CHECK (status IN ('draft', 'scheduled', 'published', 'archived')),
+ADD COLUMN published_at TIMESTAMPTZ;
+
+-- Everything that already exists was public from the moment it was created
+UPDATE posts SET status = 'published', published_at = COALESCE(created_at, CURRENT_TIMESTAMP);
+
+-- Scheduled and published posts always know when they go (or went) live
+ALTER TABLE posts
+ADD CONSTRAINT posts_published_at_set CHECK (status NOT IN ('scheduled', 'published') OR published_at IS NOT NULL);
+
+-- The scheduler only ever looks for scheduled posts that are due
+CREATE INDEX IF NOT EXISTS posts_scheduled_idx ON posts (published_at) WHERE status = 'scheduled';
+CREATE INDEX IF NOT EXISTS posts_published_idx ON posts (published_at) WHERE status = 'published';
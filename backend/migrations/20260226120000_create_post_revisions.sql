@@ -0,0 +1,23 @@
+-- Every version of a post, newest has the highest revision number
+CREATE TABLE IF NOT EXISTS post_revisions (
+    id SERIAL PRIMARY KEY,
+    uuid UUID DEFAULT gen_random_uuid() NOT NULL UNIQUE,
+    post_id INTEGER NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    slug TEXT,
+    content TEXT NOT NULL,
+    tags JSONB NOT NULL DEFAULT '[]'::jsonb,
+    signature TEXT,
+    is_mature BOOLEAN NOT NULL DEFAULT FALSE,
+    summary TEXT,
+    author_uuid UUID REFERENCES authors(uuid) ON DELETE SET NULL,
+    edited_by UUID REFERENCES authors(uuid) ON DELETE SET NULL, -- Who saved this version, NULL for service tokens
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (post_id, revision)
+);
+
+-- Posts that already exist start with their current version
+INSERT INTO post_revisions (post_id, revision, title, slug, content, tags, signature, is_mature, summary, author_uuid, created_at)
+SELECT id, 1, title, slug, content, COALESCE(tags, '[]'::jsonb), signature, is_mature, summary, author_uuid, COALESCE(updated_at, created_at, CURRENT_TIMESTAMP)
+FROM posts;
@@ -0,0 +1,9 @@
+-- Rendered HTML for each revision, so content is only rendered once per version
+CREATE TABLE IF NOT EXISTS post_renders (
+    post_id INTEGER NOT NULL,
+    revision INTEGER NOT NULL,
+    content_html TEXT NOT NULL,
+    rendered_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (post_id, revision),
+    FOREIGN KEY (post_id, revision) REFERENCES post_revisions(post_id, revision) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+-- Each site picks how its code blocks look
+ALTER TABLE sites
+ADD COLUMN highlight_theme TEXT NOT NULL DEFAULT 'InspiredGitHub',
+ADD COLUMN highlight_class_prefix TEXT NOT NULL DEFAULT 'hl-' CHECK (highlight_class_prefix ~ '^[A-Za-z0-9_-]*$');
+
+-- Highlighted HTML depends on the class prefix, so renders are cached per prefix too
+DELETE FROM post_renders;
+ALTER TABLE post_renders
+ADD COLUMN class_prefix TEXT NOT NULL,
+DROP CONSTRAINT post_renders_pkey,
+ADD PRIMARY KEY (post_id, revision, class_prefix);
@@ -0,0 +1,21 @@
+-- Full text search over posts, titles count most, then summaries, then content
+ALTER TABLE posts ADD COLUMN search_vector TSVECTOR;
+
+CREATE OR REPLACE FUNCTION posts_search_vector_update() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search_vector :=
+        setweight(to_tsvector('english', COALESCE(NEW.title, '')), 'A') ||
+        setweight(to_tsvector('english', COALESCE(NEW.summary, '')), 'B') ||
+        setweight(to_tsvector('english', COALESCE(NEW.content, '')), 'C');
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER posts_search_vector_trigger
+BEFORE INSERT OR UPDATE OF title, summary, content ON posts
+FOR EACH ROW EXECUTE FUNCTION posts_search_vector_update();
+
+-- Fill in existing posts by firing the trigger
+UPDATE posts SET title = title;
+
+CREATE INDEX IF NOT EXISTS posts_search_vector_idx ON posts USING GIN (search_vector);
@@ -0,0 +1,6 @@
+-- Trigram indexes for typo tolerant suggestions
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS posts_title_trgm_idx ON posts USING GIN (title gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS tag_stats_tag_name_trgm_idx ON tag_stats USING GIN (tag_name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS authors_name_trgm_idx ON authors USING GIN (name gin_trgm_ops);
@@ -0,0 +1,7 @@
+-- Where the frontend shows posts, tags and authors, for sitemaps and feeds.
+-- Tags and authors only get URLs once a pattern is set.
+ALTER TABLE sites
+ADD COLUMN post_url_pattern TEXT NOT NULL DEFAULT '/posts/{slug}',
+ADD COLUMN tag_url_pattern TEXT,
+ADD COLUMN author_url_pattern TEXT,
+ADD COLUMN robots_txt TEXT;
@@ -0,0 +1,110 @@
+-- Which tags are on which posts, in the order they were given. This replaces the
+-- JSONB array on posts, and tag counts and site visibility are derived from it.
+CREATE TABLE IF NOT EXISTS post_tags (
+    post_id INTEGER NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE,
+    position INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (post_id, tag_uuid)
+);
+CREATE INDEX IF NOT EXISTS post_tags_tag_uuid_idx ON post_tags (tag_uuid);
+
+-- Every tag a post uses needs a row to point at
+INSERT INTO tag_stats (tag_name)
+SELECT DISTINCT jsonb_array_elements_text(tags) FROM posts
+WHERE jsonb_typeof(tags) = 'array'
+ON CONFLICT (tag_name) DO NOTHING;
+
+INSERT INTO post_tags (post_id, tag_uuid, position)
+SELECT p.id, t.tag_uuid, MIN(e.ord)
+FROM posts p
+CROSS JOIN LATERAL jsonb_array_elements_text(p.tags) WITH ORDINALITY AS e(name, ord)
+JOIN tag_stats t ON t.tag_name = e.name
+WHERE jsonb_typeof(p.tags) = 'array'
+GROUP BY p.id, t.tag_uuid;
+
+ALTER TABLE posts DROP COLUMN tags;
+
+-- A post's tags as the JSON array the API has always returned
+CREATE OR REPLACE FUNCTION post_tag_names(post INTEGER) RETURNS JSONB AS $$
+    SELECT COALESCE(jsonb_agg(t.tag_name ORDER BY pt.position, t.tag_name), '[]'::jsonb)
+    FROM post_tags pt
+    JOIN tag_stats t ON t.tag_uuid = pt.tag_uuid
+    WHERE pt.post_id = post
+$$ LANGUAGE SQL STABLE;
+
+-- Counts start over from what posts actually have, they had drifted and gone negative
+UPDATE tag_stats t
+SET use_count = (SELECT COUNT(*) FROM post_tags pt WHERE pt.tag_uuid = t.tag_uuid);
+
+UPDATE tag_stats SET selected_count = 0 WHERE selected_count IS NULL;
+ALTER TABLE tag_stats
+ALTER COLUMN use_count SET NOT NULL,
+ALTER COLUMN selected_count SET NOT NULL;
+
+-- A tag is shown on a site while a post on that site has it. Only ever grew before.
+DELETE FROM tag_sites;
+INSERT INTO tag_sites (tag_uuid, site_id)
+SELECT DISTINCT pt.tag_uuid, ps.site_id
+FROM post_tags pt
+JOIN post_sites ps ON ps.post_id = pt.post_id;
+
+-- Drop a tag from the given sites where no remaining post still shows it there.
+-- Deleted posts are left out through the join on posts, so this works whichever of
+-- post_tags and post_sites a cascade clears first.
+CREATE OR REPLACE FUNCTION prune_tag_sites(tags UUID[], sites INTEGER[]) RETURNS VOID AS $$
+    DELETE FROM tag_sites ts
+    WHERE ts.tag_uuid = ANY(tags)
+    AND (sites IS NULL OR ts.site_id = ANY(sites))
+    AND NOT EXISTS (
+        SELECT 1 FROM post_tags pt
+        JOIN post_sites ps ON ps.post_id = pt.post_id
+        JOIN posts p ON p.id = pt.post_id
+        WHERE pt.tag_uuid = ts.tag_uuid AND ps.site_id = ts.site_id
+    )
+$$ LANGUAGE SQL;
+
+CREATE OR REPLACE FUNCTION post_tags_changed() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        UPDATE tag_stats SET use_count = use_count + 1 WHERE tag_uuid = NEW.tag_uuid;
+
+        INSERT INTO tag_sites (tag_uuid, site_id)
+        SELECT NEW.tag_uuid, site_id FROM post_sites WHERE post_id = NEW.post_id
+        ON CONFLICT DO NOTHING;
+
+        RETURN NEW;
+    END IF;
+
+    UPDATE tag_stats SET use_count = use_count - 1 WHERE tag_uuid = OLD.tag_uuid;
+    PERFORM prune_tag_sites(ARRAY[OLD.tag_uuid], NULL);
+
+    RETURN OLD;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER post_tags_changed
+AFTER INSERT OR DELETE ON post_tags
+FOR EACH ROW EXECUTE FUNCTION post_tags_changed();
+
+CREATE OR REPLACE FUNCTION post_sites_changed() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO tag_sites (tag_uuid, site_id)
+        SELECT tag_uuid, NEW.site_id FROM post_tags WHERE post_id = NEW.post_id
+        ON CONFLICT DO NOTHING;
+
+        RETURN NEW;
+    END IF;
+
+    PERFORM prune_tag_sites(
+        ARRAY(SELECT tag_uuid FROM post_tags WHERE post_id = OLD.post_id),
+        ARRAY[OLD.site_id]
+    );
+
+    RETURN OLD;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER post_sites_changed
+AFTER INSERT OR DELETE ON post_sites
+FOR EACH ROW EXECUTE FUNCTION post_sites_changed();
@@ -0,0 +1,91 @@
+-- How many posts on each site have a tag. A row only exists while there's at least one.
+ALTER TABLE tag_sites ADD COLUMN IF NOT EXISTS use_count INTEGER NOT NULL DEFAULT 0;
+
+DELETE FROM tag_sites;
+INSERT INTO tag_sites (tag_uuid, site_id, use_count)
+SELECT pt.tag_uuid, ps.site_id, COUNT(*)
+FROM post_tags pt
+JOIN post_sites ps ON ps.post_id = pt.post_id
+GROUP BY pt.tag_uuid, ps.site_id;
+
+-- Add `delta` to the count of every tag on every site given, dropping rows that reach
+-- zero. Every post, tag and site combination is counted once when its post_tags and
+-- post_sites rows both exist, and taken off once when the first of them goes. That's
+-- why removals are counted BEFORE DELETE, when a post is deleted both cascades have
+-- run by the time AFTER triggers fire and neither could see the other's rows.
+CREATE OR REPLACE FUNCTION count_tag_sites(tags UUID[], sites INTEGER[], delta INTEGER) RETURNS VOID AS $$
+BEGIN
+    IF delta > 0 THEN
+        INSERT INTO tag_sites (tag_uuid, site_id, use_count)
+        SELECT tag, site, delta FROM UNNEST(tags) AS tag, UNNEST(sites) AS site
+        ON CONFLICT (tag_uuid, site_id) DO UPDATE SET use_count = tag_sites.use_count + delta;
+    ELSE
+        -- Updating rather than upserting, the site or tag might be what's being deleted
+        UPDATE tag_sites SET use_count = use_count + delta
+        WHERE tag_uuid = ANY(tags) AND site_id = ANY(sites);
+
+        DELETE FROM tag_sites
+        WHERE tag_uuid = ANY(tags) AND site_id = ANY(sites) AND use_count <= 0;
+    END IF;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION post_tags_changed() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        UPDATE tag_stats SET use_count = use_count + 1 WHERE tag_uuid = NEW.tag_uuid;
+        PERFORM count_tag_sites(
+            ARRAY[NEW.tag_uuid],
+            ARRAY(SELECT site_id FROM post_sites WHERE post_id = NEW.post_id),
+            1
+        );
+        RETURN NEW;
+    END IF;
+
+    UPDATE tag_stats SET use_count = use_count - 1 WHERE tag_uuid = OLD.tag_uuid;
+    PERFORM count_tag_sites(
+        ARRAY[OLD.tag_uuid],
+        ARRAY(SELECT site_id FROM post_sites WHERE post_id = OLD.post_id),
+        -1
+    );
+    RETURN OLD;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION post_sites_changed() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM count_tag_sites(
+            ARRAY(SELECT tag_uuid FROM post_tags WHERE post_id = NEW.post_id),
+            ARRAY[NEW.site_id],
+            1
+        );
+        RETURN NEW;
+    END IF;
+
+    PERFORM count_tag_sites(
+        ARRAY(SELECT tag_uuid FROM post_tags WHERE post_id = OLD.post_id),
+        ARRAY[OLD.site_id],
+        -1
+    );
+    RETURN OLD;
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS post_tags_changed ON post_tags;
+CREATE TRIGGER post_tags_added
+AFTER INSERT ON post_tags
+FOR EACH ROW EXECUTE FUNCTION post_tags_changed();
+CREATE TRIGGER post_tags_removed
+BEFORE DELETE ON post_tags
+FOR EACH ROW EXECUTE FUNCTION post_tags_changed();
+
+DROP TRIGGER IF EXISTS post_sites_changed ON post_sites;
+CREATE TRIGGER post_sites_added
+AFTER INSERT ON post_sites
+FOR EACH ROW EXECUTE FUNCTION post_sites_changed();
+CREATE TRIGGER post_sites_removed
+BEFORE DELETE ON post_sites
+FOR EACH ROW EXECUTE FUNCTION post_sites_changed();
+
+DROP FUNCTION IF EXISTS prune_tag_sites(UUID[], INTEGER[]);
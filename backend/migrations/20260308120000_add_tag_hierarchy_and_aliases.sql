@@ -0,0 +1,35 @@
+-- Tags can sit under a broader tag, e.g. "axum" under "rust"
+ALTER TABLE tag_stats
+ADD COLUMN IF NOT EXISTS parent_uuid UUID REFERENCES tag_stats(tag_uuid) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS tag_stats_parent_uuid_idx ON tag_stats (parent_uuid);
+
+-- Other names for a tag. Posts tagged with an alias get the tag itself, and filters
+-- on an alias find the tag's posts.
+CREATE TABLE IF NOT EXISTS tag_aliases (
+    alias TEXT PRIMARY KEY,
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS tag_aliases_tag_uuid_idx ON tag_aliases (tag_uuid);
+
+-- The uuids of tags merged away, so links to them still find the tag they went into
+CREATE TABLE IF NOT EXISTS tag_redirects (
+    old_uuid UUID PRIMARY KEY,
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE
+);
+
+-- Swap aliases for the tags they stand for, keeping the order given
+CREATE OR REPLACE FUNCTION resolve_tag_names(names TEXT[]) RETURNS TEXT[] AS $$
+    SELECT ARRAY(
+        SELECT COALESCE(t.tag_name, given.name)
+        FROM UNNEST(names) WITH ORDINALITY AS given(name, position)
+        LEFT JOIN tag_aliases a ON a.alias = given.name
+        LEFT JOIN tag_stats t ON t.tag_uuid = a.tag_uuid
+        ORDER BY given.position
+    )
+$$ LANGUAGE SQL STABLE;
+
+-- The tag a uuid stands for now, following merges
+CREATE OR REPLACE FUNCTION resolve_tag_uuid(tag UUID) RETURNS UUID AS $$
+    SELECT COALESCE((SELECT tag_uuid FROM tag_redirects WHERE old_uuid = tag), tag)
+$$ LANGUAGE SQL STABLE;
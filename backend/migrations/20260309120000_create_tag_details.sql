@@ -0,0 +1,16 @@
+-- What a site says about a tag on its landing page. Kept apart from tag_sites, which
+-- only holds tags while the site has posts with them.
+CREATE TABLE IF NOT EXISTS tag_details (
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    -- Markdown
+    description TEXT,
+    -- A CSS hex colour, e.g. #1e90ff
+    colour TEXT CHECK (colour ~ '^#([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$'),
+    -- An emoji or the name of an icon, left to the frontend to draw
+    icon TEXT,
+    cover_image TEXT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (tag_uuid, site_id)
+);
+CREATE INDEX IF NOT EXISTS tag_details_site_id_idx ON tag_details (site_id);
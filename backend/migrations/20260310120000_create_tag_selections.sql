@@ -0,0 +1,51 @@
+-- A random key for each day, used to fingerprint the clients selecting tags. Once a
+-- day's key is deleted its fingerprints can't be traced back to a client, or matched
+-- up with the same client's on other days.
+CREATE TABLE IF NOT EXISTS tag_selection_salts (
+    day DATE PRIMARY KEY,
+    salt TEXT NOT NULL
+);
+
+-- The clients that have selected a tag on a site each day, so each counts once a day
+CREATE TABLE IF NOT EXISTS tag_selection_clients (
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    fingerprint TEXT NOT NULL,
+    PRIMARY KEY (tag_uuid, site_id, day, fingerprint)
+);
+CREATE INDEX IF NOT EXISTS tag_selection_clients_day_idx ON tag_selection_clients (day);
+
+-- Selections of each tag on each site by the hour
+CREATE TABLE IF NOT EXISTS tag_selections (
+    tag_uuid UUID NOT NULL REFERENCES tag_stats(tag_uuid) ON DELETE CASCADE,
+    site_id INTEGER NOT NULL REFERENCES sites(id) ON DELETE CASCADE,
+    bucket TIMESTAMPTZ NOT NULL,
+    selections INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (tag_uuid, site_id, bucket)
+);
+CREATE INDEX IF NOT EXISTS tag_selections_bucket_idx ON tag_selections (bucket);
+
+-- Selections of a tag in the last `hours`, on one site or every site for NULL
+CREATE OR REPLACE FUNCTION tag_popularity(tag UUID, site INTEGER, hours INTEGER) RETURNS BIGINT AS $$
+    SELECT COALESCE(SUM(selections), 0)::BIGINT
+    FROM tag_selections
+    WHERE tag_uuid = tag
+    AND (site IS NULL OR site_id = site)
+    AND bucket > NOW() - make_interval(hours => hours)
+$$ LANGUAGE SQL STABLE;
+
+-- Selections of a tag, each worth half as much every `half_life` hours. Past 1000
+-- halvings they're worth nothing, rather than underflowing.
+CREATE OR REPLACE FUNCTION tag_trending(tag UUID, site INTEGER, half_life DOUBLE PRECISION)
+RETURNS DOUBLE PRECISION AS $$
+    SELECT COALESCE(SUM(
+        selections * POWER(
+            0.5::DOUBLE PRECISION,
+            LEAST(EXTRACT(EPOCH FROM NOW() - bucket) / 3600 / half_life, 1000)::DOUBLE PRECISION
+        )
+    ), 0)
+    FROM tag_selections
+    WHERE tag_uuid = tag
+    AND (site IS NULL OR site_id = site)
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1,9 @@
+-- How many of each site's published posts have each tag. tag_sites counts drafts and
+-- scheduled posts too, so anything shown publicly goes by this instead.
+CREATE OR REPLACE VIEW published_tag_sites AS
+SELECT pt.tag_uuid, ps.site_id, COUNT(*)::INTEGER AS use_count
+FROM post_tags pt
+JOIN post_sites ps ON ps.post_id = pt.post_id
+JOIN posts p ON p.id = pt.post_id
+WHERE p.status = 'published' AND p.published_at <= NOW()
+GROUP BY pt.tag_uuid, ps.site_id;
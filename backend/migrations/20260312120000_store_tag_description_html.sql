@@ -0,0 +1,5 @@
+-- Descriptions are rendered when they're set rather than on every read. The class
+-- prefix they were highlighted with is kept so a site changing its prefix gets them
+-- rendered afresh.
+ALTER TABLE tag_details ADD COLUMN IF NOT EXISTS description_html TEXT;
+ALTER TABLE tag_details ADD COLUMN IF NOT EXISTS description_class_prefix TEXT;
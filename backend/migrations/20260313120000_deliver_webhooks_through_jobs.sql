@@ -0,0 +1,11 @@
+-- Webhook deliveries are sent by jobs now. Deliveries still waiting get a job each,
+-- picking up where their retries left off.
+INSERT INTO jobs (kind, payload, attempts, max_attempts, run_at)
+SELECT 'deliver_webhook', jsonb_build_object('delivery', uuid), attempts, 12, next_attempt_at
+FROM webhook_deliveries
+WHERE status = 'pending';
+
+DROP INDEX IF EXISTS webhook_deliveries_due_idx;
+
+-- Finished jobs are pruned by age
+CREATE INDEX IF NOT EXISTS jobs_done_idx ON jobs (finished_at) WHERE status = 'done';
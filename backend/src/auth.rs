@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const TOKEN_PREFIX: &str = "amt_";
+
+/// Cookie carrying the session token for browser logins
+pub const SESSION_COOKIE: &str = "ametrine_session";
+
+/// How long a login session lasts before the user has to sign in again
+pub const SESSION_TTL_HOURS: i64 = 12;
+
+/// What a caller is allowed to do, ordered from least to most privileged
+#[derive(
+    Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Capability {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Capability {
+    /// The highest role a credential with this capability can act as
+    pub fn ceiling(self) -> Role {
+        match self {
+            Self::Read => Role::Viewer,
+            Self::Write => Role::Editor,
+            Self::Admin => Role::Owner,
+        }
+    }
+}
+
+/// A user's role on a site, ordered from least to most privileged
+#[derive(
+    Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Role {
+    /// Can read the admin surface
+    Viewer,
+    /// Can submit posts as themselves
+    Contributor,
+    /// Can write and edit their own posts
+    Author,
+    /// Can edit any post on the site
+    Editor,
+    /// Can manage the site itself, its roles and tokens
+    Owner,
+}
+
+/// Generate a random 64 character hex string
+pub fn random_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Generate a new raw API token. This is only ever shown to the user once.
+pub fn generate_token() -> String {
+    format!("{}{}", TOKEN_PREFIX, random_secret())
+}
+
+/// Hash a raw token for storage and lookup
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
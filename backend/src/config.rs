@@ -1,5 +1,6 @@
 use ::config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
+use std::collections::HashMap;
 
 // The App config
 #[derive(Debug, Deserialize, Clone)]
@@ -8,7 +9,146 @@ pub struct AppConfig {
     pub run_migrations: bool,
     pub server_addr: String,
     pub allow_debug_headers: bool,
+    /// Give requests from this machine without a token full access, for first time setup.
+    /// Never granted to requests carrying forwarding headers, or with `trusted_proxy` on.
+    #[serde(default)]
+    pub local_admin: bool,
+    /// A reverse proxy on this machine forwards requests here, so connecting from
+    /// loopback says nothing about where a request came from
+    #[serde(default)]
+    pub trusted_proxy: bool,
     pub gpg_email: Option<String>,
+    pub oidc: Option<OidcConfig>,
+    /// Refuse post updates and deletes that don't send an If-Match header
+    #[serde(default)]
+    pub require_if_match: bool,
+    #[serde(default)]
+    pub pagination: PaginationConfig,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    #[serde(default)]
+    pub popularity: PopularityConfig,
+}
+
+// Page sizes for listings
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PaginationConfig {
+    /// Used when a request doesn't give a limit
+    pub default_limit: i64,
+    /// The largest limit a listing accepts
+    pub max_limit: i64,
+    /// Overrides of `max_limit` by endpoint name, e.g. "posts" or "admin_tags"
+    pub max_limits: HashMap<String, i64>,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        Self {
+            default_limit: 100,
+            max_limit: 500,
+            max_limits: HashMap::new(),
+        }
+    }
+}
+
+impl PaginationConfig {
+    pub fn max_limit(&self, endpoint: &str) -> i64 {
+        self.max_limits
+            .get(endpoint)
+            .copied()
+            .unwrap_or(self.max_limit)
+    }
+
+    /// The override for `endpoint`, or `default` for endpoints that don't go by the
+    /// global `max_limit`
+    pub fn max_limit_or(&self, endpoint: &str, default: i64) -> i64 {
+        self.max_limits.get(endpoint).copied().unwrap_or(default)
+    }
+}
+
+// Background job workers
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// How many jobs this server runs at once
+    pub workers: usize,
+    /// Hours finished jobs are kept for before being cleared out
+    pub keep_done_hours: i32,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            keep_done_hours: 7 * 24,
+        }
+    }
+}
+
+// How tag selections are counted
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PopularityConfig {
+    /// Windows popularity is counted over, in hours or days, e.g. "24h" or "7d"
+    pub windows: Vec<String>,
+    /// The window `sort=popularity` uses when a request doesn't give one
+    pub default_window: String,
+    /// Hours until a selection counts half as much toward trending
+    pub trending_half_life_hours: f64,
+    /// Header a reverse proxy puts the client's address in, e.g. "x-real-ip". Only set
+    /// this when every request comes through a proxy that overwrites it, or clients
+    /// can pick their own address.
+    pub client_ip_header: Option<String>,
+}
+
+impl Default for PopularityConfig {
+    fn default() -> Self {
+        Self {
+            windows: vec!["24h".to_string(), "7d".to_string(), "30d".to_string()],
+            default_window: "7d".to_string(),
+            trending_half_life_hours: 24.0,
+            client_ip_header: None,
+        }
+    }
+}
+
+impl PopularityConfig {
+    /// A window's length in hours, if it's one of the configured windows
+    pub fn window_hours(&self, window: &str) -> Option<i32> {
+        if !self.windows.iter().any(|w| w == window) {
+            return None;
+        }
+        parse_window(window)
+    }
+
+    /// How long selections have to be kept for the longest window
+    pub fn retention_hours(&self) -> i32 {
+        self.windows
+            .iter()
+            .filter_map(|w| parse_window(w))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn parse_window(window: &str) -> Option<i32> {
+    let (count, unit) = window.split_at_checked(window.len().checked_sub(1)?)?;
+    let count: i32 = count.parse().ok().filter(|c| *c > 0)?;
+    match unit {
+        "h" => Some(count),
+        "d" => count.checked_mul(24),
+        _ => None,
+    }
+}
+
+// OpenID Connect provider used for editor logins
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
 }
 
 // Load up the config
@@ -19,6 +159,41 @@ impl AppConfig {
             .add_source(Environment::default())
             .build()?;
 
-        s.try_deserialize()
+        let config: Self = s.try_deserialize()?;
+
+        let popularity = &config.popularity;
+        if let Some(bad) = popularity
+            .windows
+            .iter()
+            .find(|w| parse_window(w).is_none())
+        {
+            return Err(ConfigError::Message(format!(
+                "popularity.windows: \"{}\" isn't a number of hours or days like \"24h\" or \"7d\"",
+                bad
+            )));
+        }
+        if popularity
+            .window_hours(&popularity.default_window)
+            .is_none()
+        {
+            return Err(ConfigError::Message(
+                "popularity.default_window must be one of popularity.windows".to_string(),
+            ));
+        }
+        if config.local_admin && config.trusted_proxy {
+            return Err(ConfigError::Message(
+                "local_admin can't be used with trusted_proxy, every proxied request would be local"
+                    .to_string(),
+            ));
+        }
+
+        let half_life = popularity.trending_half_life_hours;
+        if half_life.is_nan() || half_life <= 0.0 {
+            return Err(ConfigError::Message(
+                "popularity.trending_half_life_hours must be more than 0".to_string(),
+            ));
+        }
+
+        Ok(config)
     }
 }
@@ -13,5 +13,30 @@ pub async fn setup_database(config: &AppConfig) -> anyhow::Result<PgPool> {
         println!("Migrations executed");
     }
 
+    if config.local_admin {
+        seed_local_site(&pool).await?;
+    }
+
     Ok(pool)
 }
+
+// Local setup falls back to the site holding bit 1, so a fresh database gets a
+// localhost site to start from
+async fn seed_local_site(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let created = sqlx::query!(
+        r#"
+        INSERT INTO sites (domain, site_mask_bit, requires_auth)
+        SELECT 'localhost', 1, TRUE
+        WHERE NOT EXISTS (SELECT 1 FROM sites)
+        ON CONFLICT DO NOTHING
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    if created.rows_affected() > 0 {
+        println!("Created a localhost site for local setup");
+    }
+
+    Ok(())
+}
@@ -11,6 +11,24 @@ pub struct AppError {
     pub message: Option<String>,
     pub debug: Option<String>,
     pub is_local: bool,
+    /// Request fields that were wrong, for errors caused by what the client sent
+    pub fields: Vec<FieldError>,
+}
+
+/// One bad field in a request and what's wrong with it
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -21,6 +39,8 @@ struct ErrorBody {
     message: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     debug: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
 }
 
 impl AppError {
@@ -32,6 +52,7 @@ impl AppError {
             message: None,
             debug: None,
             is_local: false,
+            fields: Vec::new(),
         }
     }
 
@@ -47,9 +68,15 @@ impl AppError {
         self
     }
 
+    /// List the request fields that were wrong
+    pub fn with_fields(mut self, fields: Vec<FieldError>) -> Self {
+        self.fields = fields;
+        self
+    }
+
     /// Check site identity to determine if we can show debug info
     pub fn at_site(mut self, site: &SiteIdentity) -> Self {
-        self.is_local = site.domain.starts_with("localhost") || site.domain.starts_with("127.0.0.1");
+        self.is_local = site.local_admin;
         self
     }
 
@@ -65,6 +92,22 @@ impl AppError {
     pub fn unauthorized() -> Self {
         Self::new(StatusCode::UNAUTHORIZED)
     }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN)
+    }
+
+    pub fn conflict() -> Self {
+        Self::new(StatusCode::CONFLICT)
+    }
+
+    pub fn precondition_failed() -> Self {
+        Self::new(StatusCode::PRECONDITION_FAILED)
+    }
+
+    pub fn precondition_required() -> Self {
+        Self::new(StatusCode::PRECONDITION_REQUIRED)
+    }
 }
 
 // Allow automatic conversion from SQL errors
@@ -94,6 +137,7 @@ impl IntoResponse for AppError {
             status: status_name,
             message: self.message,
             debug: if self.is_local { self.debug } else { None },
+            fields: self.fields,
         });
 
         (self.status, body).into_response()
@@ -1,16 +1,35 @@
+use crate::auth::{Capability, Role, SESSION_COOKIE, hash_token};
 use crate::config::AppConfig;
 use crate::error::AppError;
 use axum::{
-    extract::{FromRef, FromRequestParts},
-    http::request::Parts,
+    extract::{ConnectInfo, FromRef, FromRequestParts, OptionalFromRequestParts},
+    http::{
+        HeaderMap,
+        header::{AUTHORIZATION, COOKIE, IF_MATCH},
+        request::Parts,
+    },
 };
 use sqlx::PgPool;
+use std::net::SocketAddr;
+use uuid::Uuid;
 
+#[derive(Clone)]
 pub struct SiteIdentity {
-    pub mask: i32,
+    pub id: i32,
     pub domain: String,
     pub requires_auth: bool,
     pub gpg_email: Option<String>,
+    /// Start of the class names used for highlighted code on this site
+    pub highlight_class_prefix: String,
+    /// The request came from this machine with `local_admin` on, so it gets setup
+    /// access without a token
+    pub local_admin: bool,
+}
+
+impl SiteIdentity {
+    pub fn is_local(&self) -> bool {
+        self.domain.starts_with("localhost") || self.domain.starts_with("127.0.0.1")
+    }
 }
 
 impl<S> FromRequestParts<S> for SiteIdentity
@@ -22,6 +41,11 @@ where
     type Rejection = AppError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Already resolved by another extractor on this request
+        if let Some(site) = parts.extensions.get::<SiteIdentity>() {
+            return Ok(site.clone());
+        }
+
         let pool = PgPool::from_ref(state);
         let config = AppConfig::from_ref(state);
 
@@ -42,27 +66,372 @@ where
             }
         }
 
+        let local_admin = config.local_admin && from_loopback(parts, &config);
+
         let site_result = sqlx::query!(
-            "SELECT site_mask_bit, requires_auth FROM sites WHERE domain = $1",
+            "SELECT id, requires_auth, highlight_class_prefix FROM sites WHERE domain = $1",
             host
         )
         .fetch_optional(&pool)
         .await?;
 
-        let (mask, requires_auth) = match site_result {
-            Some(s) => (s.site_mask_bit, s.requires_auth.unwrap_or(false)),
-            None if host.starts_with("localhost") || host.starts_with("127.0.0.1") => {
-                // Default identity for localhost setup/admin
-                (1, true)
+        let (id, requires_auth, highlight_class_prefix) = match site_result {
+            Some(s) => (
+                s.id,
+                s.requires_auth.unwrap_or(false),
+                s.highlight_class_prefix,
+            ),
+            None if local_admin => {
+                // Default identity for local setup/admin, the site holding bit 1
+                let fallback = sqlx::query!(
+                    "SELECT id, highlight_class_prefix FROM sites ORDER BY (site_mask_bit = 1) IS TRUE DESC, id LIMIT 1"
+                )
+                .fetch_optional(&pool)
+                .await?;
+                match fallback {
+                    Some(s) => (s.id, true, s.highlight_class_prefix),
+                    None => (0, true, "hl-".to_string()),
+                }
             }
             None => return Err(AppError::unauthorized()),
         };
 
-        Ok(SiteIdentity {
-            mask,
+        let site = SiteIdentity {
+            id,
             domain: host,
             requires_auth,
             gpg_email: config.gpg_email.clone(),
+            highlight_class_prefix,
+            local_admin,
+        };
+        parts.extensions.insert(site.clone());
+
+        Ok(site)
+    }
+}
+
+// Headers a proxy adds to say who it's forwarding for
+const FORWARDING_HEADERS: [&str; 3] = ["forwarded", "x-forwarded-for", "x-real-ip"];
+
+// Whether the connection itself comes from this machine. The Host header can't be
+// trusted for this, and neither can anything a proxy on this machine forwards.
+fn from_loopback(parts: &Parts, config: &AppConfig) -> bool {
+    let proxied = config.trusted_proxy
+        || FORWARDING_HEADERS
+            .iter()
+            .copied()
+            .chain(config.popularity.client_ip_header.as_deref())
+            .any(|name| parts.headers.contains_key(name));
+
+    !proxied
+        && parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(addr)| addr.ip().is_loopback())
+}
+
+/// Find the raw credential on a request, either a bearer token or the session cookie
+pub fn raw_credential(headers: &HeaderMap) -> Result<Option<&str>, AppError> {
+    if let Some(header) = headers.get(AUTHORIZATION) {
+        return header
+            .to_str()
+            .ok()
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|t| Some(t.trim()))
+            .ok_or_else(|| AppError::unauthorized().with_message("Malformed Authorization header"));
+    }
+
+    let session = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value);
+
+    Ok(session)
+}
+
+/// An API token presented as `Authorization: Bearer <token>`, or a login session
+pub struct ApiToken {
+    pub sites: Vec<i32>,
+    pub capability: Capability,
+    pub author_uuid: Option<Uuid>,
+}
+
+impl<S> OptionalFromRequestParts<S> for ApiToken
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let Some(raw) = raw_credential(&parts.headers)? else {
+            return Ok(None);
+        };
+
+        let pool = PgPool::from_ref(state);
+
+        // Look the token up and record its use in one go
+        let token = sqlx::query_as::<_, (Vec<i32>, Capability, Option<Uuid>)>(
+            r#"
+            UPDATE api_tokens
+            SET last_used_at = NOW()
+            WHERE token_hash = $1
+            AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING
+                ARRAY(SELECT site_id FROM api_token_sites WHERE token_id = api_tokens.id),
+                capability,
+                author_uuid
+            "#,
+        )
+        .bind(hash_token(raw))
+        .fetch_optional(&pool)
+        .await?
+        .ok_or_else(|| AppError::unauthorized().with_message("Invalid or revoked token"))?;
+
+        Ok(Some(ApiToken {
+            sites: token.0,
+            capability: token.1,
+            author_uuid: token.2,
+        }))
+    }
+}
+
+/// Who is making a request and what they may do on each site
+pub struct Actor {
+    pub author_uuid: Option<Uuid>,
+    /// Highest role the credential allows, whatever the grants say
+    ceiling: Role,
+    /// Sites the credential itself covers, `None` for all of them
+    sites: Option<Vec<i32>>,
+    /// Per site roles when acting as an author, `None` for service tokens and local admin
+    grants: Option<Vec<(i32, Role)>>,
+    site: SiteIdentity,
+}
+
+impl Actor {
+    /// The actor's role on a site
+    pub fn role_at(&self, site_id: i32) -> Option<Role> {
+        let role = match (&self.grants, &self.sites) {
+            (Some(grants), _) => grants
+                .iter()
+                .find(|(s, _)| *s == site_id)
+                .map(|(_, r)| *r)?,
+            (None, Some(sites)) if !sites.contains(&site_id) => return None,
+            (None, _) => self.ceiling,
+        };
+        Some(role.min(self.ceiling))
+    }
+
+    /// The lowest role the actor holds across all of the given sites
+    pub fn role_on(&self, site_ids: &[i32]) -> Option<Role> {
+        if site_ids.is_empty() {
+            return None;
+        }
+        site_ids.iter().map(|id| self.role_at(*id)).min().flatten()
+    }
+
+    /// Require at least `role` on the current site
+    pub fn require(&self, role: Role) -> Result<(), AppError> {
+        self.require_on(&[self.site.id], role)
+    }
+
+    /// Require at least `role` on every one of the given sites
+    pub fn require_on(&self, site_ids: &[i32], role: Role) -> Result<(), AppError> {
+        match self.role_on(site_ids) {
+            Some(r) if r >= role => Ok(()),
+            _ => Err(AppError::forbidden()
+                .with_message(format!("Requires the {:?} role", role))
+                .at_site(&self.site)),
+        }
+    }
+
+    /// Require at least `role` on every site there is, for things that aren't kept per site
+    pub async fn require_everywhere(&self, pool: &PgPool, role: Role) -> Result<(), AppError> {
+        let site_ids = sqlx::query_scalar!("SELECT id FROM sites")
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&self.site))?;
+        self.require_on(&site_ids, role)
+    }
+
+    /// Require the actor may write content by `author_uuid` shown on the given sites.
+    /// Editors can write for anyone, everyone else only as themselves.
+    pub fn require_content(
+        &self,
+        site_ids: &[i32],
+        author_uuid: Option<Uuid>,
+        role: Role,
+    ) -> Result<(), AppError> {
+        match self.role_on(site_ids) {
+            Some(r) if r >= Role::Editor => Ok(()),
+            Some(r) if r >= role && author_uuid.is_some() && author_uuid == self.author_uuid => {
+                Ok(())
+            }
+            _ => Err(AppError::forbidden()
+                .with_message("Not allowed to edit this content")
+                .at_site(&self.site)),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for Actor
+where
+    PgPool: FromRef<S>,
+    AppConfig: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let site = SiteIdentity::from_request_parts(parts, state).await?;
+        if !site.requires_auth {
+            return Err(AppError::unauthorized().at_site(&site));
+        }
+
+        let token = <ApiToken as OptionalFromRequestParts<S>>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| e.at_site(&site))?;
+
+        let Some(token) = token else {
+            // Local setup/admin without a token, only when turned on in the config
+            if site.local_admin {
+                return Ok(Actor {
+                    author_uuid: None,
+                    ceiling: Role::Owner,
+                    sites: None,
+                    grants: None,
+                    site,
+                });
+            }
+            return Err(AppError::unauthorized().at_site(&site));
+        };
+
+        if !token.sites.contains(&site.id) {
+            return Err(AppError::forbidden()
+                .with_message("Token is not valid for this site")
+                .at_site(&site));
+        }
+
+        let grants = match token.author_uuid {
+            Some(author_uuid) => Some(
+                sqlx::query_as::<_, (i32, Role)>(
+                    "SELECT site_id, role FROM site_roles WHERE author_uuid = $1",
+                )
+                .bind(author_uuid)
+                .fetch_all(&PgPool::from_ref(state))
+                .await
+                .map_err(|e| AppError::from(e).at_site(&site))?,
+            ),
+            None => None,
+        };
+
+        Ok(Actor {
+            author_uuid: token.author_uuid,
+            ceiling: token.capability.ceiling(),
+            sites: Some(token.sites),
+            grants,
+            site,
         })
     }
 }
+
+/// The `If-Match` header, for changes that should only go ahead against a known version
+pub struct IfMatch {
+    tags: Option<Vec<String>>,
+    required: bool,
+}
+
+impl IfMatch {
+    /// Match only the given ETag, for changes built on a version already read
+    pub fn exactly(etag: String) -> Self {
+        IfMatch {
+            tags: Some(vec![etag]),
+            required: true,
+        }
+    }
+
+    /// Check the current ETag against the header, weak tags never match
+    pub fn check(&self, current: &str) -> Result<(), AppError> {
+        match &self.tags {
+            None if self.required => Err(AppError::precondition_required()
+                .with_message("This request needs an If-Match header")),
+            None => Ok(()),
+            Some(tags) if tags.iter().any(|t| t == "*" || t == current) => Ok(()),
+            Some(_) => Err(AppError::precondition_failed()
+                .with_message("The resource has changed since it was fetched")),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for IfMatch
+where
+    AppConfig: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let tags = parts
+            .headers
+            .get_all(IF_MATCH)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>();
+
+        Ok(IfMatch {
+            tags: (!tags.is_empty()).then_some(tags),
+            required: AppConfig::from_ref(state).require_if_match,
+        })
+    }
+}
+
+/// Who a request seems to come from, good enough to tell clients apart but never
+/// stored as is
+pub struct Client {
+    pub addr: String,
+}
+
+impl<S> FromRequestParts<S> for Client
+where
+    AppConfig: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = AppConfig::from_ref(state);
+
+        // A proxy's header wins when configured, its last address being the one it added
+        let forwarded = config
+            .popularity
+            .client_ip_header
+            .as_deref()
+            .and_then(|name| parts.headers.get(name))
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.rsplit(',').next())
+            .map(|addr| addr.trim().to_string())
+            .filter(|addr| !addr.is_empty());
+
+        let addr = forwarded
+            .or_else(|| {
+                parts
+                    .extensions
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| addr.ip().to_string())
+            })
+            .unwrap_or_default();
+
+        Ok(Client { addr })
+    }
+}
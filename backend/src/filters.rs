@@ -0,0 +1,334 @@
+use crate::error::{AppError, FieldError};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
+
+// Tags of the post being filtered, to finish with a condition on t.tag_name
+const POST_HAS_TAG: &str = "SELECT 1 FROM post_tags pt \
+    JOIN tag_stats t ON t.tag_uuid = pt.tag_uuid \
+    WHERE pt.post_id = posts.id";
+
+/// Which posts to list, sent as a JSON body or built from a query string.
+/// Every condition given has to hold.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostFilter {
+    pub tags: TagFilter,
+    pub author: Option<Uuid>,
+    pub created: DateRange,
+    pub updated: DateRange,
+    pub mature: MatureFilter,
+    /// Only signed posts when true, only unsigned when false
+    pub signed: Option<bool>,
+}
+
+/// Tag names, aliases included
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagFilter {
+    /// Posts must have every one of these
+    pub all: Vec<String>,
+    /// Posts must have at least one of these
+    pub any: Vec<String>,
+    /// Posts must have none of these
+    pub none: Vec<String>,
+}
+
+/// From `after` (inclusive) up to `before` (exclusive)
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct DateRange {
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatureFilter {
+    #[default]
+    Include,
+    Exclude,
+    Only,
+}
+
+impl PostFilter {
+    /// Add the filter's conditions to a WHERE clause, each starting with AND
+    pub fn push_sql(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        if !self.tags.all.is_empty() {
+            query
+                .push(" AND NOT EXISTS (SELECT 1 FROM UNNEST(resolve_tag_names(")
+                .push_bind(self.tags.all.clone())
+                .push(format!(
+                    ")) AS wanted(name) WHERE NOT EXISTS ({} AND t.tag_name = wanted.name))",
+                    POST_HAS_TAG
+                ));
+        }
+        if !self.tags.any.is_empty() {
+            query
+                .push(format!(
+                    " AND EXISTS ({} AND t.tag_name = ANY(resolve_tag_names(",
+                    POST_HAS_TAG
+                ))
+                .push_bind(self.tags.any.clone())
+                .push(")))");
+        }
+        if !self.tags.none.is_empty() {
+            query
+                .push(format!(
+                    " AND NOT EXISTS ({} AND t.tag_name = ANY(resolve_tag_names(",
+                    POST_HAS_TAG
+                ))
+                .push_bind(self.tags.none.clone())
+                .push(")))");
+        }
+
+        if let Some(author) = self.author {
+            query.push(" AND author_uuid = ").push_bind(author);
+        }
+
+        for (column, range) in [("created_at", self.created), ("updated_at", self.updated)] {
+            if let Some(after) = range.after {
+                query.push(format!(" AND {} >= ", column)).push_bind(after);
+            }
+            if let Some(before) = range.before {
+                query.push(format!(" AND {} < ", column)).push_bind(before);
+            }
+        }
+
+        match self.mature {
+            MatureFilter::Include => {}
+            MatureFilter::Exclude => {
+                query.push(" AND NOT is_mature");
+            }
+            MatureFilter::Only => {
+                query.push(" AND is_mature");
+            }
+        }
+
+        match self.signed {
+            Some(true) => {
+                query.push(" AND signature IS NOT NULL");
+            }
+            Some(false) => {
+                query.push(" AND signature IS NULL");
+            }
+            None => {}
+        }
+    }
+}
+
+/// The query string form of [`PostFilter`]. Tag lists are comma separated and dates
+/// can be RFC 3339 times or plain days, e.g.
+/// `?tags=rust,web&not_tags=meta&created_after=2024-01-01&mature=exclude`
+#[derive(Deserialize, Debug, Default)]
+pub struct PostFilterQuery {
+    /// A single tag the posts must have
+    pub tag: Option<String>,
+    pub tags: Option<String>,
+    pub any_tags: Option<String>,
+    pub not_tags: Option<String>,
+    pub author: Option<String>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub updated_after: Option<String>,
+    pub updated_before: Option<String>,
+    pub mature: Option<String>,
+    pub signed: Option<String>,
+}
+
+impl PostFilterQuery {
+    /// Check every field, listing all the bad ones in the error
+    pub fn parse(&self) -> Result<PostFilter, AppError> {
+        let mut errors = Vec::new();
+
+        let mut all = split_tags(self.tags.as_deref());
+        all.extend(self.tag.clone());
+
+        let author = self
+            .author
+            .as_deref()
+            .and_then(|raw| match Uuid::parse_str(raw) {
+                Ok(uuid) => Some(uuid),
+                Err(_) => {
+                    errors.push(FieldError::new("author", "Must be an author's uuid"));
+                    None
+                }
+            });
+
+        let mut date = |field: &str, raw: &Option<String>| {
+            raw.as_deref().and_then(|raw| {
+                let parsed = parse_date(raw);
+                if parsed.is_none() {
+                    errors.push(FieldError::new(
+                        field,
+                        "Must be an RFC 3339 time or a YYYY-MM-DD date",
+                    ));
+                }
+                parsed
+            })
+        };
+        let created = DateRange {
+            after: date("created_after", &self.created_after),
+            before: date("created_before", &self.created_before),
+        };
+        let updated = DateRange {
+            after: date("updated_after", &self.updated_after),
+            before: date("updated_before", &self.updated_before),
+        };
+
+        let mature = match self.mature.as_deref() {
+            None | Some("include") => MatureFilter::Include,
+            Some("exclude") => MatureFilter::Exclude,
+            Some("only") => MatureFilter::Only,
+            Some(_) => {
+                errors.push(FieldError::new(
+                    "mature",
+                    "Must be include, exclude or only",
+                ));
+                MatureFilter::Include
+            }
+        };
+
+        let signed = match self.signed.as_deref() {
+            None => None,
+            Some("true") | Some("1") => Some(true),
+            Some("false") | Some("0") => Some(false),
+            Some(_) => {
+                errors.push(FieldError::new("signed", "Must be true or false"));
+                None
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(AppError::bad_request()
+                .with_message("Invalid query parameters")
+                .with_fields(errors));
+        }
+
+        Ok(PostFilter {
+            tags: TagFilter {
+                all,
+                any: split_tags(self.any_tags.as_deref()),
+                none: split_tags(self.not_tags.as_deref()),
+            },
+            author,
+            created,
+            updated,
+            mature,
+            signed,
+        })
+    }
+}
+
+fn split_tags(raw: Option<&str>) -> Vec<String> {
+    raw.unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// A plain day means midnight UTC at its start
+fn parse_date(raw: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(raw) {
+        return Some(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+        .ok()
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields a query was refused for
+    fn rejected_fields(query: PostFilterQuery) -> Vec<String> {
+        let Err(error) = query.parse() else {
+            panic!("the query was accepted");
+        };
+        assert_eq!(error.status, axum::http::StatusCode::BAD_REQUEST);
+        error.fields.into_iter().map(|f| f.field).collect()
+    }
+
+    #[test]
+    fn parses_a_full_query() {
+        let query = PostFilterQuery {
+            tag: Some("rust".to_string()),
+            tags: Some("web, axum,,".to_string()),
+            not_tags: Some("meta".to_string()),
+            created_after: Some("2024-01-01".to_string()),
+            updated_before: Some("2024-02-01T12:00:00+02:00".to_string()),
+            mature: Some("exclude".to_string()),
+            signed: Some("1".to_string()),
+            ..Default::default()
+        };
+        let Ok(filter) = query.parse() else {
+            panic!("a valid query was refused");
+        };
+
+        assert_eq!(filter.tags.all, ["web", "axum", "rust"]);
+        assert_eq!(filter.tags.none, ["meta"]);
+        assert!(filter.tags.any.is_empty());
+        assert_eq!(
+            filter.created.after.unwrap().to_rfc3339(),
+            "2024-01-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            filter.updated.before.unwrap().to_rfc3339(),
+            "2024-02-01T10:00:00+00:00"
+        );
+        assert_eq!(filter.mature, MatureFilter::Exclude);
+        assert_eq!(filter.signed, Some(true));
+    }
+
+    #[test]
+    fn refuses_each_bad_field() {
+        assert_eq!(
+            rejected_fields(PostFilterQuery {
+                author: Some("someone".to_string()),
+                ..Default::default()
+            }),
+            ["author"]
+        );
+        assert_eq!(
+            rejected_fields(PostFilterQuery {
+                created_before: Some("01/02/2024".to_string()),
+                ..Default::default()
+            }),
+            ["created_before"]
+        );
+        assert_eq!(
+            rejected_fields(PostFilterQuery {
+                mature: Some("sometimes".to_string()),
+                ..Default::default()
+            }),
+            ["mature"]
+        );
+        assert_eq!(
+            rejected_fields(PostFilterQuery {
+                signed: Some("yes".to_string()),
+                ..Default::default()
+            }),
+            ["signed"]
+        );
+    }
+
+    #[test]
+    fn lists_every_bad_field() {
+        let fields = rejected_fields(PostFilterQuery {
+            author: Some("someone".to_string()),
+            created_after: Some("soon".to_string()),
+            updated_after: Some("2024-13-01".to_string()),
+            signed: Some("maybe".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            fields,
+            ["author", "created_after", "updated_after", "signed"]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{publishing, webhooks};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How long an idle worker waits before looking for work again
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// A running job is taken back by another worker once this runs out, e.g. after a crash
+const LEASE_SECS: f64 = 300.0;
+
+// Retries back off from 10 seconds, doubling up to an hour, for kinds without their own
+const FIRST_RETRY_SECS: i64 = 10;
+const MAX_RETRY_SECS: i64 = 60 * 60;
+const MAX_ATTEMPTS: i32 = 5;
+
+// How often finished jobs are cleared out
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Errors are only kept in the table up to this length
+const MAX_ERROR_LEN: usize = 2000;
+
+/// The kinds of background work there are handlers for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// Publish every scheduled post that's due, queued for each post's publish time
+    PublishScheduled,
+    /// Send one webhook delivery, retried until the receiver takes it
+    DeliverWebhook,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PublishScheduled => "publish_scheduled",
+            Self::DeliverWebhook => "deliver_webhook",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "publish_scheduled" => Some(Self::PublishScheduled),
+            "deliver_webhook" => Some(Self::DeliverWebhook),
+            _ => None,
+        }
+    }
+
+    /// Tries before a job of this kind is left dead
+    pub fn max_attempts(self) -> i32 {
+        match self {
+            Self::DeliverWebhook => webhooks::MAX_ATTEMPTS,
+            _ => MAX_ATTEMPTS,
+        }
+    }
+
+    /// How long to wait before the next try after `attempts` failed ones
+    pub fn retry_delay(self, attempts: i32) -> i64 {
+        match self {
+            Self::DeliverWebhook => retry_delay(
+                attempts,
+                webhooks::FIRST_RETRY_SECS,
+                webhooks::MAX_RETRY_SECS,
+            ),
+            _ => retry_delay(attempts, FIRST_RETRY_SECS, MAX_RETRY_SECS),
+        }
+    }
+}
+
+/// Queue a job to run at `run_at`, or straight away. Takes a connection so jobs can be
+/// queued in the same transaction as the write they follow from, and are dropped with
+/// it if it rolls back.
+pub async fn enqueue(
+    conn: &mut PgConnection,
+    kind: JobKind,
+    payload: Value,
+    run_at: Option<DateTime<Utc>>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO jobs (kind, payload, run_at, max_attempts)
+        VALUES ($1, $2, COALESCE($3, NOW()), $4)
+        RETURNING uuid
+        "#,
+        kind.as_str(),
+        payload,
+        run_at,
+        kind.max_attempts()
+    )
+    .fetch_one(conn)
+    .await
+}
+
+#[derive(sqlx::FromRow)]
+struct ClaimedJob {
+    id: i64,
+    uuid: Uuid,
+    kind: String,
+    payload: Value,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+// Do the work for a job. Errors are retried, so handlers have to be safe to run twice.
+async fn run(pool: &PgPool, job: &ClaimedJob) -> anyhow::Result<()> {
+    let kind = JobKind::parse(&job.kind)
+        .ok_or_else(|| anyhow::anyhow!("No handler for job kind {}", job.kind))?;
+
+    match kind {
+        JobKind::PublishScheduled => {
+            publishing::publish_due(pool).await?;
+        }
+        JobKind::DeliverWebhook => {
+            let delivery: Uuid = serde_json::from_value(job.payload["delivery"].clone())?;
+            webhooks::deliver(pool, delivery, job.attempts, job.max_attempts).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// How long to wait before the next try after `attempts` failed ones, starting at
+/// `first_secs` and doubling up to `max_secs`
+pub fn retry_delay(attempts: i32, first_secs: i64, max_secs: i64) -> i64 {
+    let doublings = attempts.clamp(1, 20) as u32 - 1;
+    first_secs.saturating_mul(1 << doublings).min(max_secs)
+}
+
+/// Claim and run one job that's due, returning whether there was one
+pub async fn work_one(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    // SKIP LOCKED lets any number of workers, on any number of servers, share the table
+    let job = sqlx::query_as::<_, ClaimedJob>(
+        r#"
+        UPDATE jobs
+        SET
+            status = 'running',
+            attempts = attempts + 1,
+            locked_until = NOW() + make_interval(secs => $1)
+        WHERE id = (
+            SELECT id FROM jobs
+            WHERE (status = 'queued' AND run_at <= NOW())
+            OR (status = 'running' AND locked_until < NOW())
+            ORDER BY run_at
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, uuid, kind, payload, attempts, max_attempts
+        "#,
+    )
+    .bind(LEASE_SECS)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(job) = job else {
+        return Ok(false);
+    };
+
+    match run(pool, &job).await {
+        Ok(()) => {
+            sqlx::query!(
+                r#"
+                UPDATE jobs
+                SET status = 'done', finished_at = NOW(), locked_until = NULL, last_error = NULL
+                WHERE id = $1
+                "#,
+                job.id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Err(e) => {
+            let mut error = format!("{:#}", e);
+            if let Some((cut, _)) = error.char_indices().nth(MAX_ERROR_LEN) {
+                error.truncate(cut);
+            }
+            let dead = job.attempts >= job.max_attempts;
+            let delay = match JobKind::parse(&job.kind) {
+                Some(kind) => kind.retry_delay(job.attempts),
+                None => retry_delay(job.attempts, FIRST_RETRY_SECS, MAX_RETRY_SECS),
+            };
+            if dead {
+                eprintln!("Job {} ({}) failed for good: {}", job.uuid, job.kind, error);
+            }
+
+            sqlx::query!(
+                r#"
+                UPDATE jobs
+                SET
+                    status = CASE WHEN $2 THEN 'dead' ELSE 'queued' END,
+                    finished_at = CASE WHEN $2 THEN NOW() END,
+                    run_at = NOW() + make_interval(secs => $3),
+                    locked_until = NULL,
+                    last_error = $4
+                WHERE id = $1
+                "#,
+                job.id,
+                dead,
+                delay as f64,
+                error
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Start `workers` tasks taking jobs off the queue
+pub fn spawn_workers(pool: PgPool, workers: usize) {
+    for _ in 0..workers {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                match work_one(&pool).await {
+                    Ok(true) => {}
+                    Ok(false) => tokio::time::sleep(POLL_INTERVAL).await,
+                    Err(e) => {
+                        eprintln!("Failed to run background jobs: {}", e);
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Drop jobs that finished more than `keep_done_hours` ago. Dead jobs are kept until
+/// someone retries or looks into them.
+pub async fn prune(pool: &PgPool, keep_done_hours: i32) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM jobs
+        WHERE status = 'done'
+        AND finished_at < NOW() - make_interval(hours => $1)
+        "#,
+        keep_done_hours
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Start clearing out finished jobs in the background
+pub fn spawn_pruner(pool: PgPool, keep_done_hours: i32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = prune(&pool, keep_done_hours).await {
+                eprintln!("Failed to prune finished jobs: {}", e);
+            }
+        }
+    });
+}
@@ -1,13 +1,27 @@
+mod auth;
 mod config;
 mod db;
 mod error;
 mod extractors;
+mod filters;
 mod gpg;
+mod jobs;
 mod models;
+mod oidc;
+mod pagination;
 mod params;
+mod popularity;
+mod publishing;
+mod render;
 mod routes;
+#[cfg(test)]
+mod testing;
+mod urls;
+mod visibility;
+mod webhooks;
 use crate::config::AppConfig;
 use axum::extract::FromRef;
+use std::net::SocketAddr;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -32,6 +46,10 @@ async fn main() -> anyhow::Result<()> {
     let settings = AppConfig::load().expect("Failed to load config.toml");
 
     let pool = db::setup_database(&settings).await?;
+    jobs::spawn_workers(pool.clone(), settings.jobs.workers);
+    jobs::spawn_pruner(pool.clone(), settings.jobs.keep_done_hours);
+    popularity::spawn_pruner(pool.clone(), settings.popularity.retention_hours());
+
     let state = AppState {
         db: pool,
         config: settings.clone(),
@@ -41,7 +59,12 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(&settings.server_addr)
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 
     Ok(())
 }
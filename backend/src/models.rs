@@ -16,6 +16,44 @@ pub struct Post {
     pub is_mature: bool,
     pub summary: Option<String>,
     pub author_uuid: Option<Uuid>,
+    pub status: PostStatus,
+    pub published_at: Option<DateTime<Utc>>,
+    /// Only filled in by searches
+    #[sqlx(default)]
+    pub snippet: Option<String>,
+    /// The value a listing was sorted on, for cursors
+    #[sqlx(default)]
+    pub sort_key: Option<String>,
+}
+
+/// Where a post is in its lifecycle, only published posts are shown publicly
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum PostStatus {
+    /// Still being written
+    Draft,
+    /// Goes live by itself once `published_at` passes
+    Scheduled,
+    /// Live, as long as `published_at` has passed
+    Published,
+    /// Taken down but kept around
+    Archived,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct PostRevision {
+    pub revision: i32,
+    pub title: String,
+    pub slug: Option<String>,
+    pub content: String,
+    pub tags: serde_json::Value,
+    pub signature: Option<String>,
+    pub is_mature: bool,
+    pub summary: Option<String>,
+    pub author_uuid: Option<Uuid>,
+    pub edited_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -24,7 +62,19 @@ pub struct Tag {
     pub tag_uuid: Uuid,
     pub use_count: i32,
     pub selected_count: i32,
+    pub parent_uuid: Option<Uuid>,
     pub visibility_mask: i32,
+    pub sites: Vec<i32>,
+    /// How many posts have the tag on each site, by site id
+    pub site_counts: serde_json::Value,
+    /// Other names that resolve to this tag
+    pub aliases: Vec<String>,
+    /// Selections across all sites in each popularity window, e.g. {"24h": 3, "7d": 12}
+    pub popularity: serde_json::Value,
+    /// The value a listing was sorted on, for cursors
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub sort_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
@@ -44,4 +94,5 @@ pub struct AuthorSocial {
     pub handle: String,
     pub url: Option<String>,
     pub visibility_mask: i32,
+    pub sites: Vec<i32>,
 }
@@ -0,0 +1,550 @@
+use crate::{
+    error::{AppError, FieldError},
+    params::{PageBounds, SortDirection},
+};
+use axum::{
+    Json,
+    http::{HeaderName, HeaderValue, Uri, header::LINK},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Datelike};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
+
+static X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+/// Where a page starts, handed to clients as an opaque string. Points at the row just
+/// outside the page, by its sort value and uuid.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cursor {
+    /// The ordering the cursor was made for, it means nothing under any other
+    #[serde(rename = "o")]
+    pub ordering: String,
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "u")]
+    pub uuid: Uuid,
+    /// Page towards the start rather than the end
+    #[serde(rename = "b")]
+    pub backwards: bool,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub fn decode(raw: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(raw).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    /// Whether the key can be cast back to `key_type`. Cursors are only encoded, not
+    /// signed, so anything a client sends has to be checked before Postgres sees it.
+    pub fn key_fits(&self, key_type: &str) -> bool {
+        let key = self.key.trim();
+        match key_type {
+            "INTEGER" => key.parse::<i32>().is_ok(),
+            "BIGINT" => key.parse::<i64>().is_ok(),
+            "REAL" => key
+                .parse::<f32>()
+                .is_ok_and(|value| float_fits(key, value.into())),
+            "DOUBLE PRECISION" => key.parse::<f64>().is_ok_and(|value| float_fits(key, value)),
+            "TIMESTAMPTZ" => DateTime::parse_from_str(key, "%Y-%m-%d %H:%M:%S%.f%#z")
+                .is_ok_and(|at| at.year() >= 1),
+            _ => true,
+        }
+    }
+}
+
+// Rust rounds floats too big or too small for the type to infinity or zero where
+// Postgres refuses them
+fn float_fits(key: &str, value: f64) -> bool {
+    if value.is_infinite() {
+        return key.to_ascii_lowercase().contains("inf");
+    }
+    if value == 0.0 {
+        let mantissa = key.split(['e', 'E']).next().unwrap_or_default();
+        return !mantissa.chars().any(|c| ('1'..='9').contains(&c));
+    }
+    true
+}
+
+/// How the client asked to page through a listing
+pub enum PageRequest {
+    Offset {
+        limit: i64,
+        offset: i64,
+        with_total: bool,
+    },
+    /// A `cursor` was sent, empty for the first page
+    Cursor {
+        limit: i64,
+        cursor: Option<Cursor>,
+        with_total: bool,
+    },
+}
+
+impl PageRequest {
+    pub fn new(
+        bounds: PageBounds,
+        cursor: Option<&str>,
+        ordering: &str,
+        key_type: &str,
+    ) -> Result<Self, AppError> {
+        let PageBounds {
+            limit,
+            offset,
+            with_total,
+        } = bounds;
+        let Some(raw) = cursor else {
+            return Ok(PageRequest::Offset {
+                limit,
+                offset,
+                with_total,
+            });
+        };
+        if raw.is_empty() {
+            return Ok(PageRequest::Cursor {
+                limit,
+                cursor: None,
+                with_total,
+            });
+        }
+
+        let invalid = |message: &str| {
+            AppError::bad_request()
+                .with_message("Invalid query parameters")
+                .with_fields(vec![FieldError::new("cursor", message)])
+        };
+        let cursor = Cursor::decode(raw).ok_or_else(|| invalid("Not a cursor from this API"))?;
+        if cursor.ordering != ordering {
+            return Err(invalid("The cursor was made for a different sort order"));
+        }
+        if !cursor.key_fits(key_type) {
+            return Err(invalid("Not a cursor from this API"));
+        }
+
+        Ok(PageRequest::Cursor {
+            limit,
+            cursor: Some(cursor),
+            with_total,
+        })
+    }
+
+    /// Whether the client asked for a total count
+    pub fn with_total(&self) -> bool {
+        match self {
+            PageRequest::Offset { with_total, .. } | PageRequest::Cursor { with_total, .. } => {
+                *with_total
+            }
+        }
+    }
+
+    /// Rows to ask the database for, cursor pages fetch one extra to see if there's more
+    pub fn fetch_limit(&self) -> i64 {
+        match self {
+            PageRequest::Offset { limit, .. } => *limit,
+            PageRequest::Cursor { limit, .. } => limit + 1,
+        }
+    }
+
+    pub fn offset(&self) -> i64 {
+        match self {
+            PageRequest::Offset { offset, .. } => *offset,
+            PageRequest::Cursor { .. } => 0,
+        }
+    }
+
+    pub fn cursor(&self) -> Option<&Cursor> {
+        match self {
+            PageRequest::Cursor { cursor, .. } => cursor.as_ref(),
+            PageRequest::Offset { .. } => None,
+        }
+    }
+
+    pub fn backwards(&self) -> bool {
+        self.cursor().is_some_and(|c| c.backwards)
+    }
+}
+
+/// An ordering by one column with uuid to break ties, which keyset pagination can resume
+pub struct Keyset<'a> {
+    /// SQL expression sorted on
+    pub column: &'a str,
+    /// Type to cast cursor keys back to when comparing
+    pub key_type: &'a str,
+    pub uuid_column: &'a str,
+    pub direction: SortDirection,
+}
+
+impl Keyset<'_> {
+    fn direction(&self, backwards: bool) -> SortDirection {
+        match (self.direction, backwards) {
+            (SortDirection::Asc, false) | (SortDirection::Desc, true) => SortDirection::Asc,
+            _ => SortDirection::Desc,
+        }
+    }
+
+    /// Condition for rows past the cursor, whose key and uuid are bound as `$key_param`
+    /// and `$uuid_param`. Passes everything while the key is NULL.
+    pub fn condition(&self, backwards: bool, key_param: usize, uuid_param: usize) -> String {
+        let op = match self.direction(backwards) {
+            SortDirection::Asc => ">",
+            SortDirection::Desc => "<",
+        };
+        format!(
+            "(${key}::TEXT IS NULL OR ({col}, {uuid}) {op} (${key}::{ty}, ${uuid_param}))",
+            key = key_param,
+            col = self.column,
+            uuid = self.uuid_column,
+            op = op,
+            ty = self.key_type,
+            uuid_param = uuid_param
+        )
+    }
+
+    /// Add the condition for rows past `cursor` to a WHERE clause being built
+    pub fn push_condition(
+        &self,
+        query: &mut QueryBuilder<'_, Postgres>,
+        backwards: bool,
+        cursor: Option<&Cursor>,
+    ) {
+        let Some(cursor) = cursor else {
+            return;
+        };
+        let op = match self.direction(backwards) {
+            SortDirection::Asc => ">",
+            SortDirection::Desc => "<",
+        };
+        query
+            .push(format!(
+                " AND ({}, {}) {} (",
+                self.column, self.uuid_column, op
+            ))
+            .push_bind(cursor.key.clone())
+            .push(format!("::{}, ", self.key_type))
+            .push_bind(cursor.uuid)
+            .push(")");
+    }
+
+    /// ORDER BY clause, reversed when paging backwards
+    pub fn order(&self, backwards: bool) -> String {
+        let direction = self.direction(backwards).to_sql();
+        format!(
+            "{} {}, {} {}",
+            self.column, direction, self.uuid_column, direction
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+    /// Items across all pages, when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+}
+
+/// A listing as the client asked for it, a bare array for offset paging or a page
+/// with cursors (and matching `Link` headers) for cursor paging
+pub enum Listing<T> {
+    Plain { items: Vec<T>, total: Option<i64> },
+    Paged { page: Page<T>, uri: Uri },
+}
+
+impl<T> Listing<T> {
+    /// Build a listing from rows fetched for `request`, each paired with its sort key and uuid
+    pub fn new(
+        request: &PageRequest,
+        ordering: &str,
+        uri: Uri,
+        mut rows: Vec<(T, String, Uuid)>,
+    ) -> Self {
+        let PageRequest::Cursor { limit, cursor, .. } = request else {
+            return Listing::Plain {
+                items: rows.into_iter().map(|(item, _, _)| item).collect(),
+                total: None,
+            };
+        };
+
+        let has_more = rows.len() as i64 > *limit;
+        rows.truncate((*limit).max(0) as usize);
+        let backwards = request.backwards();
+        if backwards {
+            rows.reverse();
+        }
+
+        let make_cursor = |row: Option<&(T, String, Uuid)>, backwards: bool| {
+            row.map(|(_, key, uuid)| {
+                Cursor {
+                    ordering: ordering.to_string(),
+                    key: key.clone(),
+                    uuid: *uuid,
+                    backwards,
+                }
+                .encode()
+            })
+        };
+
+        // Going forwards there's more after if we over-fetched, and before if we came from a cursor.
+        // Going backwards it's the other way round.
+        let (more_after, more_before) = match backwards {
+            false => (has_more, cursor.is_some()),
+            true => (true, has_more),
+        };
+        let next_cursor = more_after
+            .then(|| make_cursor(rows.last(), false))
+            .flatten();
+        let prev_cursor = more_before
+            .then(|| make_cursor(rows.first(), true))
+            .flatten();
+
+        Listing::Paged {
+            page: Page {
+                items: rows.into_iter().map(|(item, _, _)| item).collect(),
+                next_cursor,
+                prev_cursor,
+                total: None,
+            },
+            uri,
+        }
+    }
+
+    /// The listing as a page, to send inside another response. Offset paging has no cursors.
+    pub fn into_page(self) -> Page<T> {
+        match self {
+            Listing::Plain { items, total } => Page {
+                items,
+                next_cursor: None,
+                prev_cursor: None,
+                total,
+            },
+            Listing::Paged { page, .. } => page,
+        }
+    }
+
+    /// Attach the number of items across all pages, sent as `X-Total-Count` and in the envelope
+    pub fn with_total(mut self, count: Option<i64>) -> Self {
+        match &mut self {
+            Listing::Plain { total, .. }
+            | Listing::Paged {
+                page: Page { total, .. },
+                ..
+            } => *total = count,
+        }
+        self
+    }
+}
+
+// The current URL with its cursor swapped for another
+fn link_to(uri: &Uri, cursor: &str) -> String {
+    let mut query: Vec<&str> = uri
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("cursor="))
+        .collect();
+    let cursor = format!("cursor={}", cursor);
+    query.push(&cursor);
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+impl<T: Serialize> IntoResponse for Listing<T> {
+    fn into_response(self) -> Response {
+        let (mut response, total) = match self {
+            Listing::Plain { items, total } => (Json(items).into_response(), total),
+            Listing::Paged { page, uri } => {
+                let links: Vec<String> = [(&page.next_cursor, "next"), (&page.prev_cursor, "prev")]
+                    .into_iter()
+                    .filter_map(|(cursor, rel)| {
+                        cursor
+                            .as_ref()
+                            .map(|c| format!("<{}>; rel=\"{}\"", link_to(&uri, c), rel))
+                    })
+                    .collect();
+
+                let total = page.total;
+                let mut response = Json(page).into_response();
+                if let Ok(value) = HeaderValue::from_str(&links.join(", "))
+                    && !links.is_empty()
+                {
+                    response.headers_mut().insert(LINK, value);
+                }
+                (response, total)
+            }
+        };
+
+        if let Some(total) = total {
+            response
+                .headers_mut()
+                .insert(X_TOTAL_COUNT.clone(), HeaderValue::from(total));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERING: &str = "published_at:desc";
+
+    fn bounds() -> PageBounds {
+        PageBounds {
+            limit: 20,
+            offset: 0,
+            with_total: false,
+        }
+    }
+
+    fn cursor(key: &str) -> Cursor {
+        Cursor {
+            ordering: ORDERING.to_string(),
+            key: key.to_string(),
+            uuid: Uuid::new_v4(),
+            backwards: false,
+        }
+    }
+
+    // The field an invalid cursor was reported against
+    fn rejected_field(raw: &str, ordering: &str, key_type: &str) -> String {
+        let Err(error) = PageRequest::new(bounds(), Some(raw), ordering, key_type) else {
+            panic!("{} was accepted", raw);
+        };
+        assert_eq!(error.status, axum::http::StatusCode::BAD_REQUEST);
+        error.fields[0].field.clone()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let original = Cursor {
+            backwards: true,
+            ..cursor("2024-05-01 12:30:00.5+00")
+        };
+        let decoded = Cursor::decode(&original.encode()).unwrap();
+        assert_eq!(decoded.ordering, original.ordering);
+        assert_eq!(decoded.key, original.key);
+        assert_eq!(decoded.uuid, original.uuid);
+        assert!(decoded.backwards);
+
+        let raw = original.encode();
+        let Ok(request) = PageRequest::new(bounds(), Some(&raw), ORDERING, "TIMESTAMPTZ") else {
+            panic!("a cursor from this API was refused");
+        };
+        assert!(request.backwards());
+        assert_eq!(request.fetch_limit(), 21);
+        assert_eq!(request.cursor().unwrap().uuid, original.uuid);
+    }
+
+    #[test]
+    fn empty_cursor_starts_cursor_paging() {
+        let Ok(request) = PageRequest::new(bounds(), Some(""), ORDERING, "TIMESTAMPTZ") else {
+            panic!("an empty cursor was refused");
+        };
+        assert!(matches!(request, PageRequest::Cursor { cursor: None, .. }));
+
+        let Ok(request) = PageRequest::new(bounds(), None, ORDERING, "TIMESTAMPTZ") else {
+            panic!("no cursor was refused");
+        };
+        assert!(matches!(request, PageRequest::Offset { .. }));
+    }
+
+    #[test]
+    fn tampered_cursors_are_refused() {
+        assert_eq!(
+            rejected_field("not a cursor", ORDERING, "INTEGER"),
+            "cursor"
+        );
+
+        // Valid base64, but not JSON
+        let garbage = URL_SAFE_NO_PAD.encode("{\"o\":");
+        assert_eq!(rejected_field(&garbage, ORDERING, "INTEGER"), "cursor");
+
+        // A well formed cursor whose key was edited into something Postgres can't cast
+        let edited = cursor("1; DROP TABLE posts").encode();
+        assert_eq!(rejected_field(&edited, ORDERING, "INTEGER"), "cursor");
+    }
+
+    #[test]
+    fn cursors_only_work_for_their_sort() {
+        let raw = cursor("42").encode();
+        assert_eq!(rejected_field(&raw, "title:asc", "INTEGER"), "cursor");
+        assert!(PageRequest::new(bounds(), Some(&raw), ORDERING, "INTEGER").is_ok());
+    }
+
+    #[test]
+    fn key_fits_its_type() {
+        assert!(cursor("42").key_fits("INTEGER"));
+        assert!(!cursor("4294967296").key_fits("INTEGER"));
+        assert!(cursor("4294967296").key_fits("BIGINT"));
+        assert!(!cursor("4.2").key_fits("BIGINT"));
+
+        assert!(cursor("0.25").key_fits("REAL"));
+        assert!(cursor("0").key_fits("REAL"));
+        assert!(cursor("Infinity").key_fits("DOUBLE PRECISION"));
+        // Out of range for the type, where Rust would quietly round
+        assert!(!cursor("1e39").key_fits("REAL"));
+        assert!(!cursor("1e-400").key_fits("DOUBLE PRECISION"));
+
+        assert!(cursor("2024-05-01 12:30:00.123456+00").key_fits("TIMESTAMPTZ"));
+        assert!(!cursor("yesterday").key_fits("TIMESTAMPTZ"));
+
+        // Text keys are bound as text, anything goes
+        assert!(cursor("it's fine").key_fits("TEXT"));
+    }
+
+    #[test]
+    fn keyset_conditions_follow_the_direction() {
+        let keyset = Keyset {
+            column: "p.published_at",
+            key_type: "TIMESTAMPTZ",
+            uuid_column: "p.uuid",
+            direction: SortDirection::Desc,
+        };
+        assert_eq!(
+            keyset.condition(false, 3, 4),
+            "($3::TEXT IS NULL OR (p.published_at, p.uuid) < ($3::TIMESTAMPTZ, $4))"
+        );
+        assert_eq!(
+            keyset.condition(true, 3, 4),
+            "($3::TEXT IS NULL OR (p.published_at, p.uuid) > ($3::TIMESTAMPTZ, $4))"
+        );
+        assert_eq!(keyset.order(false), "p.published_at DESC, p.uuid DESC");
+        assert_eq!(keyset.order(true), "p.published_at ASC, p.uuid ASC");
+    }
+
+    #[test]
+    fn keyset_pushes_bound_conditions() {
+        let keyset = Keyset {
+            column: "title",
+            key_type: "TEXT",
+            uuid_column: "uuid",
+            direction: SortDirection::Asc,
+        };
+
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM posts WHERE TRUE");
+        keyset.push_condition(&mut query, false, None);
+        assert_eq!(query.sql(), "SELECT * FROM posts WHERE TRUE");
+
+        let after = cursor("Hello'); --");
+        keyset.push_condition(&mut query, false, Some(&after));
+        assert_eq!(
+            query.sql(),
+            "SELECT * FROM posts WHERE TRUE AND (title, uuid) > ($1::TEXT, $2)"
+        );
+
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM posts WHERE TRUE");
+        keyset.push_condition(&mut query, true, Some(&after));
+        assert_eq!(
+            query.sql(),
+            "SELECT * FROM posts WHERE TRUE AND (title, uuid) < ($1::TEXT, $2)"
+        );
+    }
+}
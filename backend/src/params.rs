@@ -1,3 +1,4 @@
+use crate::error::{AppError, FieldError};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Clone, Copy)]
@@ -20,19 +21,74 @@ impl SortDirection {
 pub struct PaginationParams {
     pub limit: Option<String>,
     pub offset: Option<String>,
+    /// Switches to cursor paging, empty for the first page
+    pub cursor: Option<String>,
+    /// Ask for the number of items across all pages
+    pub total: Option<String>,
 }
+
+/// Paging values that have been checked
+#[derive(Debug, Clone, Copy)]
+pub struct PageBounds {
+    pub limit: i64,
+    pub offset: i64,
+    pub with_total: bool,
+}
+
 impl PaginationParams {
-    pub fn limit(&self) -> i64 {
-        self.limit
-            .as_ref()
-            .and_then(|s| s.parse::<i64>().ok())
-            .unwrap_or(100)
+    /// Parse the paging values, refusing any that are malformed or out of range.
+    /// Every bad field is listed in the error, not just the first.
+    pub fn bounds(&self, default_limit: i64, max_limit: i64) -> Result<PageBounds, AppError> {
+        let mut errors = Vec::new();
+
+        let limit = match self.limit.as_deref().map(str::parse::<i64>) {
+            None => default_limit.min(max_limit),
+            Some(Ok(limit)) if (1..=max_limit).contains(&limit) => limit,
+            Some(Ok(_)) => {
+                errors.push(FieldError::new(
+                    "limit",
+                    format!("Must be between 1 and {}", max_limit),
+                ));
+                0
+            }
+            Some(Err(_)) => {
+                errors.push(FieldError::new("limit", "Must be a whole number"));
+                0
+            }
+        };
+
+        let offset = match self.offset.as_deref().map(str::parse::<i64>) {
+            None => 0,
+            Some(Ok(offset)) if offset >= 0 => offset,
+            Some(_) => {
+                errors.push(FieldError::new("offset", "Must be a whole number, 0 or more"));
+                0
+            }
+        };
+
+        let with_total = match self.total.as_deref() {
+            None | Some("false") | Some("0") => false,
+            Some("true") | Some("1") | Some("") => true,
+            Some(_) => {
+                errors.push(FieldError::new("total", "Must be true or false"));
+                false
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(AppError::bad_request()
+                .with_message("Invalid query parameters")
+                .with_fields(errors));
+        }
+
+        Ok(PageBounds {
+            limit,
+            offset,
+            with_total,
+        })
     }
-    pub fn offset(&self) -> i64 {
-        self.offset
-            .as_ref()
-            .and_then(|s| s.parse::<i64>().ok())
-            .unwrap_or(0)
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_deref()
     }
 }
 
@@ -45,11 +101,11 @@ pub struct SortParams<T> {
 }
 
 impl<T> SortParams<T> {
-    pub fn limit(&self) -> i64 {
-        self.pagination.limit()
+    pub fn bounds(&self, default_limit: i64, max_limit: i64) -> Result<PageBounds, AppError> {
+        self.pagination.bounds(default_limit, max_limit)
     }
-    pub fn offset(&self) -> i64 {
-        self.pagination.offset()
+    pub fn cursor(&self) -> Option<&str> {
+        self.pagination.cursor()
     }
     pub fn sort(&self) -> Option<&T> {
         self.sort.as_ref()
@@ -67,11 +123,11 @@ pub struct SearchParams<T> {
 }
 
 impl<T> SearchParams<T> {
-    pub fn limit(&self) -> i64 {
-        self.sortable.limit()
+    pub fn bounds(&self, default_limit: i64, max_limit: i64) -> Result<PageBounds, AppError> {
+        self.sortable.bounds(default_limit, max_limit)
     }
-    pub fn offset(&self) -> i64 {
-        self.sortable.offset()
+    pub fn cursor(&self) -> Option<&str> {
+        self.sortable.cursor()
     }
     pub fn sort(&self) -> Option<&T> {
         self.sortable.sort()
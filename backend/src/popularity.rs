@@ -0,0 +1,159 @@
+use crate::auth::random_secret;
+use crate::extractors::Client;
+use chrono::{NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::net::IpAddr;
+use std::time::Duration;
+use uuid::Uuid;
+
+// How often old selections and salts are cleared out
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The network a client's address sits in, a /24 for IPv4 and a /64 for IPv6, so one
+/// machine can't count many times by hopping between its own addresses
+fn network(addr: &str) -> String {
+    match addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let [a, b, c, d, ..] = ip.segments();
+            format!("{a:x}:{b:x}:{c:x}:{d:x}::/64")
+        }
+        Err(_) => addr.to_string(),
+    }
+}
+
+/// A client's fingerprint for one day, keyed on its network alone. Without that day's
+/// salt it can't be traced back to the client, or matched with the same client's
+/// fingerprint on another day.
+pub fn fingerprint(salt: &str, client: &Client) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(network(&client.addr).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// The day's salt, made by whichever request needs it first
+async fn salt_for(pool: &PgPool, day: NaiveDate) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO tag_selection_salts (day, salt) VALUES ($1, $2)
+        ON CONFLICT (day) DO UPDATE SET day = EXCLUDED.day
+        RETURNING salt
+        "#,
+        day,
+        random_secret()
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Count a client selecting a tag on a site, once a day at most.
+/// Returns whether it counted.
+pub async fn record_selection(
+    pool: &PgPool,
+    tag_uuid: Uuid,
+    site_id: i32,
+    client: &Client,
+) -> Result<bool, sqlx::Error> {
+    let now = Utc::now();
+    let day = now.date_naive();
+    let salt = salt_for(pool, day).await?;
+
+    let result = sqlx::query!(
+        r#"
+        WITH fresh AS (
+            INSERT INTO tag_selection_clients (tag_uuid, site_id, day, fingerprint)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            RETURNING tag_uuid
+        ),
+        counted AS (
+            UPDATE tag_stats SET selected_count = selected_count + 1
+            WHERE tag_uuid IN (SELECT tag_uuid FROM fresh)
+        )
+        INSERT INTO tag_selections (tag_uuid, site_id, bucket, selections)
+        SELECT tag_uuid, $2, date_trunc('hour', $5::TIMESTAMPTZ, 'UTC'), 1 FROM fresh
+        ON CONFLICT (tag_uuid, site_id, bucket) DO UPDATE
+        SET selections = tag_selections.selections + 1
+        "#,
+        tag_uuid,
+        site_id,
+        day,
+        fingerprint(&salt, client),
+        now
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Drop past days' salts and fingerprints, and selections older than `retention_hours`
+pub async fn prune(pool: &PgPool, retention_hours: i32) -> Result<(), sqlx::Error> {
+    let today = Utc::now().date_naive();
+
+    sqlx::query!("DELETE FROM tag_selection_salts WHERE day < $1", today)
+        .execute(pool)
+        .await?;
+    sqlx::query!("DELETE FROM tag_selection_clients WHERE day < $1", today)
+        .execute(pool)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM tag_selections WHERE bucket < NOW() - make_interval(hours => $1)",
+        retention_hours
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Start pruning old selections in the background
+pub fn spawn_pruner(pool: PgPool, retention_hours: i32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = prune(&pool, retention_hours).await {
+                eprintln!("Failed to prune tag selections: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(addr: &str) -> Client {
+        Client {
+            addr: addr.to_string(),
+        }
+    }
+
+    #[test]
+    fn fingerprints_match_only_within_a_day() {
+        let today = fingerprint("salt-one", &client("192.0.2.1"));
+
+        assert_eq!(today, fingerprint("salt-one", &client("192.0.2.1")));
+        assert_ne!(today, fingerprint("salt-one", &client("198.51.100.1")));
+        assert_ne!(today, fingerprint("salt-two", &client("192.0.2.1")));
+        assert!(!today.contains("192.0.2.1"));
+    }
+
+    #[test]
+    fn fingerprints_cover_the_whole_network() {
+        let v4 = fingerprint("salt", &client("192.0.2.1"));
+        assert_eq!(v4, fingerprint("salt", &client("192.0.2.254")));
+        assert_ne!(v4, fingerprint("salt", &client("192.0.3.1")));
+
+        let v6 = fingerprint("salt", &client("2001:db8:1:2::1"));
+        assert_eq!(v6, fingerprint("salt", &client("2001:db8:1:2:ffff::9")));
+        assert_ne!(v6, fingerprint("salt", &client("2001:db8:1:3::1")));
+    }
+}
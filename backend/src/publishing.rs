@@ -0,0 +1,97 @@
+use crate::{
+    jobs::{self, JobKind},
+    models::{Post, PostStatus},
+    webhooks::{self, WebhookEvent},
+};
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, PgPool};
+
+/// Work out the status a post should be stored with.
+/// Publishing in the future schedules the post, scheduling in the past publishes it.
+pub fn resolve_status(
+    status: Option<PostStatus>,
+    published_at: Option<DateTime<Utc>>,
+) -> Result<(PostStatus, Option<DateTime<Utc>>), &'static str> {
+    let now = Utc::now();
+
+    // Without a status, a publish time is taken as wanting the post to go live
+    let status = status.unwrap_or(match published_at {
+        Some(_) => PostStatus::Published,
+        None => PostStatus::Draft,
+    });
+
+    match (status, published_at) {
+        (PostStatus::Scheduled, None) => Err("Scheduled posts need a published_at"),
+        (PostStatus::Published | PostStatus::Scheduled, Some(at)) if at > now => {
+            Ok((PostStatus::Scheduled, Some(at)))
+        }
+        (PostStatus::Published | PostStatus::Scheduled, at) => {
+            Ok((PostStatus::Published, Some(at.unwrap_or(now))))
+        }
+        (status, at) => Ok((status, at)),
+    }
+}
+
+/// Run anything that should happen once a post goes live, in the transaction that
+/// publishes it so the post can't go live without its hooks
+pub async fn on_published(conn: &mut PgConnection, post: &Post) -> Result<(), sqlx::Error> {
+    webhooks::enqueue_post(conn, post, WebhookEvent::PostPublished).await
+}
+
+/// Publish every scheduled post whose time has come, returning how many went live
+pub async fn publish_due(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Each row is only flipped once, so running several servers won't fire hooks twice
+    let posts = sqlx::query_as::<_, Post>(
+        r#"
+        UPDATE posts
+        SET status = 'published', updated_at = NOW()
+        WHERE status = 'scheduled'
+        AND published_at <= NOW()
+        RETURNING
+            id,
+            uuid,
+            title,
+            slug,
+            content,
+            created_at,
+            updated_at,
+            post_tag_names(id) AS tags,
+            signature,
+            is_mature,
+            summary,
+            author_uuid,
+            status,
+            published_at
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for post in &posts {
+        on_published(&mut tx, post).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(posts.len())
+}
+
+/// Queue publishing for a scheduled post's time, in the transaction that schedules it.
+/// A post moved to a later time just finds nothing due when the earlier job runs.
+pub async fn schedule_publish(conn: &mut PgConnection, post: &Post) -> Result<(), sqlx::Error> {
+    if post.status != PostStatus::Scheduled {
+        return Ok(());
+    }
+
+    jobs::enqueue(
+        conn,
+        JobKind::PublishScheduled,
+        serde_json::json!({ "post": post.uuid }),
+        post.published_at,
+    )
+    .await?;
+
+    Ok(())
+}
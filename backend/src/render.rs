@@ -0,0 +1,345 @@
+use ammonia::Builder;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd, html};
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use syntect::{
+    highlighting::ThemeSet,
+    html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+// Footnote ids are prefixed so names picked by authors can't clash with the page's own ids
+const FOOTNOTE_PREFIX: &str = "fn-";
+
+// syntect only takes a 'static class prefix, so code is highlighted with this one and
+// the site's swapped in after. Its characters are always escaped in highlighted text,
+// so it only turns up where syntect put it.
+const PREFIX_MARKER: &str = "<prefix>";
+// The marker as it comes out in stylesheets
+const CSS_PREFIX_MARKER: &str = "\\3c prefix\\3e ";
+
+// Everything not listed here is stripped from rendered content
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::default();
+    builder
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_tag_attributes("div", ["id"])
+        .add_allowed_classes("sup", ["footnote-reference", "footnote-definition-label"])
+        .add_allowed_classes("div", ["footnote-definition"]);
+    builder
+});
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEMES: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+/// Render markdown (CommonMark with GFM tables, footnotes, strikethrough and task lists)
+/// to sanitized HTML, with code blocks highlighted using classes starting with `class_prefix`
+pub fn render_markdown(markdown: &str, class_prefix: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+
+    // Highlighted blocks skip the sanitizer, which would strip their classes. They stand
+    // in as placeholders no author could guess and are swapped back in afterwards.
+    let marker = uuid::Uuid::new_v4().simple().to_string();
+    let mut blocks: Vec<String> = Vec::new();
+    let mut code: Option<(String, String)> = None;
+
+    let mut events = Vec::new();
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((lang, String::new()));
+            }
+            Event::Text(text) if code.is_some() => {
+                if let Some((_, source)) = code.as_mut() {
+                    source.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((lang, source)) = code.take() {
+                    let placeholder = format!("highlight-{}-{}", marker, blocks.len());
+                    blocks.push(highlight(&source, &lang, class_prefix));
+                    events.push(Event::Html(format!("<p>{}</p>\n", placeholder).into()));
+                }
+            }
+            Event::FootnoteReference(name) => {
+                events.push(Event::FootnoteReference(prefix_footnote(name)))
+            }
+            Event::Start(Tag::FootnoteDefinition(name)) => {
+                events.push(Event::Start(Tag::FootnoteDefinition(prefix_footnote(name))))
+            }
+            event => events.push(event),
+        }
+    }
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, events.into_iter());
+
+    let mut clean = SANITIZER.clean(&unsafe_html).to_string();
+    for (i, block) in blocks.iter().enumerate() {
+        clean = clean.replacen(&format!("<p>highlight-{}-{}</p>", marker, i), block, 1);
+    }
+    clean
+}
+
+fn prefix_footnote(name: CowStr<'_>) -> CowStr<'_> {
+    format!("{}{}", FOOTNOTE_PREFIX, name).into()
+}
+
+fn class_style(class_prefix: &str) -> ClassStyle {
+    match class_prefix {
+        "" => ClassStyle::Spaced,
+        _ => ClassStyle::SpacedPrefixed {
+            prefix: PREFIX_MARKER,
+        },
+    }
+}
+
+// The prefix at the start of a CSS class name, where a digit has to be escaped. Site
+// prefixes are only letters, digits, - and _.
+fn css_prefix(prefix: &str) -> String {
+    match prefix.chars().next() {
+        Some(c) if c.is_ascii_digit() => format!("\\{:x} {}", c as u32, &prefix[1..]),
+        _ => prefix.to_string(),
+    }
+}
+
+// A code block as class based spans, plain text if the language is unknown
+fn highlight(source: &str, lang: &str, class_prefix: &str) -> String {
+    let syntax = SYNTAXES
+        .find_syntax_by_token(lang)
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+
+    let mut generator =
+        ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, class_style(class_prefix));
+    for line in LinesWithEndings::from(source) {
+        if generator
+            .parse_html_for_line_which_includes_newline(line)
+            .is_err()
+        {
+            // Fall back to the block without any highlighting
+            return format!(
+                "<pre class=\"{}code\"><code>{}</code></pre>\n",
+                class_prefix,
+                ammonia::clean_text(source)
+            );
+        }
+    }
+
+    let lang: String = lang
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let lang_class = if lang.is_empty() {
+        String::new()
+    } else {
+        format!(" class=\"language-{}\"", lang)
+    };
+
+    format!(
+        "<pre class=\"{}code\"><code{}>{}</code></pre>\n",
+        class_prefix,
+        lang_class,
+        generator.finalize().replace(PREFIX_MARKER, class_prefix)
+    )
+}
+
+/// Whether syntax highlighting has a theme by this name
+pub fn is_theme(name: &str) -> bool {
+    THEMES.themes.contains_key(name)
+}
+
+/// Stylesheet for highlighted code using `theme` and classes starting with `class_prefix`
+pub fn theme_css(theme: &str, class_prefix: &str) -> Option<String> {
+    let theme = THEMES.themes.get(theme)?;
+    let css = css_for_theme_with_class_style(theme, class_style(class_prefix)).ok()?;
+    Some(css.replace(CSS_PREFIX_MARKER, &css_prefix(class_prefix)))
+}
+
+/// Marks the start and end of a match in raw search snippets. Private use characters,
+/// so they can't turn up in what authors write.
+pub const SNIPPET_START: char = '\u{E000}';
+pub const SNIPPET_STOP: char = '\u{E001}';
+
+/// Escape a raw search snippet and turn its match markers into `<mark>` tags
+pub fn render_snippet(raw: &str) -> String {
+    let mut html = String::with_capacity(raw.len());
+    let mut marking = false;
+    for c in raw.chars() {
+        match c {
+            SNIPPET_START if !marking => {
+                html.push_str("<mark>");
+                marking = true;
+            }
+            SNIPPET_STOP if marking => {
+                html.push_str("</mark>");
+                marking = false;
+            }
+            SNIPPET_START | SNIPPET_STOP => {}
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    if marking {
+        html.push_str("</mark>");
+    }
+    html
+}
+
+/// Rendered HTML for the latest revision of each post, rendering and caching any
+/// that haven't been rendered with this class prefix yet
+pub async fn rendered_content(
+    pool: &PgPool,
+    posts: &[(i32, &str)],
+    class_prefix: &str,
+) -> Result<HashMap<i32, String>, sqlx::Error> {
+    let ids: Vec<i32> = posts.iter().map(|(id, _)| *id).collect();
+
+    let cached = sqlx::query!(
+        r#"
+        SELECT p.id AS "id!", latest.revision, r.content_html AS "content_html?"
+        FROM UNNEST($1::INTEGER[]) AS p(id)
+        CROSS JOIN LATERAL (
+            SELECT MAX(revision) AS revision FROM post_revisions WHERE post_id = p.id
+        ) AS latest
+        LEFT JOIN post_renders r
+            ON r.post_id = p.id AND r.revision = latest.revision AND r.class_prefix = $2
+        "#,
+        &ids,
+        class_prefix
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut rendered = HashMap::with_capacity(posts.len());
+    for row in cached {
+        let html = match row.content_html {
+            Some(html) => html,
+            None => {
+                let Some((_, content)) = posts.iter().find(|(id, _)| *id == row.id) else {
+                    continue;
+                };
+                let html = render_markdown(content, class_prefix);
+                if let Some(revision) = row.revision {
+                    cache_render(
+                        &mut *pool.acquire().await?,
+                        row.id,
+                        revision,
+                        class_prefix,
+                        &html,
+                    )
+                    .await?;
+                }
+                html
+            }
+        };
+        rendered.insert(row.id, html);
+    }
+
+    Ok(rendered)
+}
+
+/// Store the rendered HTML for a revision
+pub async fn cache_render(
+    conn: &mut PgConnection,
+    post_id: i32,
+    revision: i32,
+    class_prefix: &str,
+    content_html: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO post_renders (post_id, revision, class_prefix, content_html)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING
+        "#,
+        post_id,
+        revision,
+        class_prefix,
+        content_html
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_scripts_and_handlers() {
+        let html = render_markdown(
+            "Hi <script>alert(1)</script>\n\n<img src=\"x.png\" onerror=\"alert(1)\">\n\n[link](javascript:alert(1))",
+            "hl-",
+        );
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(!html.contains("onerror"), "{}", html);
+        assert!(!html.contains("javascript:"), "{}", html);
+        assert!(html.contains("<img src=\"x.png\""), "{}", html);
+    }
+
+    #[test]
+    fn placeholders_in_content_stay_text() {
+        // Whatever an author guesses, the marker is new each render
+        let markdown = "<p>highlight-00000000000000000000000000000000-0</p>\n\n\
+            highlight-00000000000000000000000000000000-0\n\n\
+            ```html\n<script>alert(1)</script>\n```\n\n\
+            <script>alert(2)</script>";
+        let html = render_markdown(markdown, "hl-");
+
+        assert!(!html.contains("<script"), "{}", html);
+        assert_eq!(html.matches("<pre").count(), 1, "{}", html);
+        assert_eq!(
+            html.matches("highlight-00000000000000000000000000000000-0")
+                .count(),
+            2,
+            "{}",
+            html
+        );
+        // The code block's own markup comes out escaped
+        assert!(html.contains("&lt;"), "{}", html);
+    }
+
+    #[test]
+    fn code_is_highlighted_with_the_site_prefix() {
+        let html = render_markdown("```rust\nlet s = \"<prefix>\";\n```", "site-");
+        assert!(html.contains("<pre class=\"site-code\"><code class=\"language-rust\">"));
+        assert!(html.contains("class=\"site-source site-rust\""), "{}", html);
+        // Code that looks like the marker is left alone
+        assert!(html.contains("&lt;prefix&gt;"), "{}", html);
+        assert!(!html.contains("<prefix>"), "{}", html);
+
+        let plain = render_markdown("```rust\nfn main() {}\n```", "");
+        assert!(plain.contains("class=\"source rust\""), "{}", plain);
+    }
+
+    #[test]
+    fn theme_css_uses_the_site_prefix() {
+        let css = theme_css("InspiredGitHub", "site-").unwrap();
+        assert!(css.contains(".site-code"), "{}", css);
+        assert!(!css.contains("prefix"), "{}", css);
+
+        // Class names can't start with a digit unescaped
+        let css = theme_css("InspiredGitHub", "1x-").unwrap();
+        assert!(css.contains(".\\31 x-code"), "{}", css);
+
+        assert!(theme_css("no-such-theme", "site-").is_none());
+    }
+}
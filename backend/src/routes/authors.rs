@@ -1,4 +1,4 @@
-use crate::{error::AppError, extractors::SiteIdentity, models::{Author, AuthorSocial}};
+use crate::{auth::Role, error::AppError, extractors::{Actor, SiteIdentity}, models::{Author, AuthorSocial}, visibility::Visibility, webhooks::{self, WebhookEvent}};
 use axum::{
     Json,
     extract::{Path, State},
@@ -23,6 +23,7 @@ pub struct SocialResponse {
     pub handle: String,
     pub url: Option<String>,
     pub visibility_mask: i32,
+    pub sites: Vec<i32>,
 }
 
 pub async fn get_authors(
@@ -36,10 +37,22 @@ pub async fn get_authors(
     let mut response = Vec::new();
     for author in authors {
         let socials = sqlx::query_as::<_, AuthorSocial>(
-            "SELECT id, author_uuid, platform, handle, url, visibility_mask FROM author_socials WHERE author_uuid = $1 AND (visibility_mask & $2) > 0"
+            r#"
+            SELECT
+                a.id, a.author_uuid, a.platform, a.handle, a.url,
+                COALESCE((
+                    SELECT BIT_OR(s.site_mask_bit) FROM author_social_sites x
+                    JOIN sites s ON s.id = x.site_id
+                    WHERE x.social_id = a.id
+                ), 0) AS visibility_mask,
+                ARRAY(SELECT site_id FROM author_social_sites WHERE social_id = a.id ORDER BY site_id) AS sites
+            FROM author_socials a
+            WHERE a.author_uuid = $1
+            AND a.id IN (SELECT social_id FROM author_social_sites WHERE site_id = $2)
+            "#
         )
         .bind(author.uuid)
-        .bind(site.mask)
+        .bind(site.id)
         .fetch_all(&pool)
         .await?
         .into_iter()
@@ -48,6 +61,7 @@ pub async fn get_authors(
             handle: s.handle,
             url: s.url,
             visibility_mask: s.visibility_mask,
+            sites: s.sites,
         })
         .collect();
 
@@ -72,12 +86,12 @@ pub struct CreateAuthorRequest {
 
 pub async fn create_author(
     State(pool): State<PgPool>,
-    site: SiteIdentity,
+    actor: Actor,
     Json(payload): Json<CreateAuthorRequest>,
 ) -> Result<Json<AuthorResponse>, AppError> {
-    if !site.requires_auth {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+    actor.require(Role::Editor)?;
+
+    let mut tx = pool.begin().await?;
 
     let author = sqlx::query_as::<_, Author>(
         "INSERT INTO authors (name, bio, signing_email) VALUES ($1, $2, $3) RETURNING id, uuid, name, bio, signing_email"
@@ -85,9 +99,15 @@ pub async fn create_author(
     .bind(&payload.name)
     .bind(&payload.bio)
     .bind(&payload.signing_email)
-    .fetch_one(&pool)
+    .fetch_one(&mut *tx)
     .await?;
 
+    // Authors aren't tied to a site, so every site's webhooks hear about them
+    let data = serde_json::json!({ "uuid": author.uuid, "name": author.name });
+    webhooks::enqueue(&mut tx, None, WebhookEvent::AuthorCreated, data).await?;
+
+    tx.commit().await?;
+
     Ok(Json(AuthorResponse {
         uuid: author.uuid,
         name: author.name,
@@ -102,29 +122,52 @@ pub struct AddSocialRequest {
     pub platform: String,
     pub handle: String,
     pub url: Option<String>,
-    pub visibility_mask: i32,
+    #[serde(flatten)]
+    pub visibility: Visibility,
 }
 
 pub async fn add_social(
     State(pool): State<PgPool>,
     site: SiteIdentity,
+    actor: Actor,
     Path(author_uuid): Path<Uuid>,
     Json(payload): Json<AddSocialRequest>,
 ) -> Result<StatusCode, AppError> {
-    if !site.requires_auth {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+    let site_ids = payload
+        .visibility
+        .resolve(&pool)
+        .await?
+        .ok_or_else(|| {
+            AppError::bad_request()
+                .with_message("Either visibility_mask or sites is required")
+                .at_site(&site)
+        })?;
+
+    // Authors can manage their own socials, editors anyone's
+    actor.require_content(&site_ids, Some(author_uuid), Role::Author)?;
 
-    sqlx::query(
-        "INSERT INTO author_socials (author_uuid, platform, handle, url, visibility_mask) VALUES ($1, $2, $3, $4, $5)"
+    let mut tx = pool.begin().await?;
+
+    let social_id: i32 = sqlx::query_scalar(
+        "INSERT INTO author_socials (author_uuid, platform, handle, url) VALUES ($1, $2, $3, $4) RETURNING id"
     )
     .bind(author_uuid)
     .bind(&payload.platform)
     .bind(&payload.handle)
     .bind(&payload.url)
-    .bind(payload.visibility_mask)
-    .execute(&pool)
+    .fetch_one(&mut *tx)
     .await?;
 
+    sqlx::query("INSERT INTO author_social_sites (social_id, site_id) SELECT $1, UNNEST($2::INTEGER[])")
+        .bind(social_id)
+        .bind(&site_ids)
+        .execute(&mut *tx)
+        .await?;
+
+    let data = serde_json::json!({ "uuid": author_uuid });
+    webhooks::enqueue(&mut tx, Some(&site_ids), WebhookEvent::AuthorUpdated, data).await?;
+
+    tx.commit().await?;
+
     Ok(StatusCode::CREATED)
 }
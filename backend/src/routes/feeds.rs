@@ -0,0 +1,575 @@
+use crate::{
+    error::AppError,
+    extractors::SiteIdentity,
+    filters::{MatureFilter, PostFilter, TagFilter},
+    models::Post,
+    render::rendered_content,
+    routes::posts::push_post_listing,
+    urls::{SiteUrls, site_url},
+};
+use axum::{
+    extract::{OriginalUri, Path, Query, State, rejection::QueryRejection},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::{collections::HashMap, fmt::Write};
+use uuid::Uuid;
+
+// Feed readers only care about recent posts
+const FEED_LENGTH: i64 = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FeedFormat {
+    fn from_file(file: &str) -> Option<Self> {
+        match file {
+            "rss.xml" => Some(Self::Rss),
+            "atom.xml" => Some(Self::Atom),
+            "feed.json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Rss => "application/rss+xml; charset=utf-8",
+            Self::Atom => "application/atom+xml; charset=utf-8",
+            Self::Json => "application/feed+json; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FeedParams {
+    /// Mature posts are left out of feeds unless asked for
+    pub mature: Option<MatureFilter>,
+}
+
+#[derive(sqlx::FromRow)]
+struct FeedEntry {
+    #[sqlx(flatten)]
+    post: Post,
+    author_name: Option<String>,
+}
+
+// What a feed is narrowed down to
+enum FeedScope {
+    Site,
+    Tag(String),
+    Author(Uuid, String),
+}
+
+pub async fn site_feed(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    Path(file): Path<String>,
+    params: Result<Query<FeedParams>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let request = FeedRequest::new(&site, headers, uri.path(), &file, params)?;
+    feed(&pool, &site, request, FeedScope::Site).await
+}
+
+pub async fn tag_feed(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    Path((tag, file)): Path<(String, String)>,
+    params: Result<Query<FeedParams>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let request = FeedRequest::new(&site, headers, uri.path(), &file, params)?;
+    feed(&pool, &site, request, FeedScope::Tag(tag)).await
+}
+
+pub async fn author_feed(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    Path((identifier, file)): Path<(String, String)>,
+    params: Result<Query<FeedParams>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let request = FeedRequest::new(&site, headers, uri.path(), &file, params)?;
+
+    let author_uuid = Uuid::parse_str(&identifier).map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+    let name = sqlx::query_scalar!("SELECT name FROM authors WHERE uuid = $1", author_uuid)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .ok_or_else(|| {
+            AppError::not_found()
+                .with_message("Unknown author")
+                .at_site(&site)
+        })?;
+
+    feed(&pool, &site, request, FeedScope::Author(author_uuid, name)).await
+}
+
+// Everything about the request a feed needs apart from its scope
+struct FeedRequest {
+    format: FeedFormat,
+    mature: MatureFilter,
+    headers: HeaderMap,
+    self_url: String,
+}
+
+impl FeedRequest {
+    fn new(
+        site: &SiteIdentity,
+        headers: HeaderMap,
+        path: &str,
+        file: &str,
+        params: Result<Query<FeedParams>, QueryRejection>,
+    ) -> Result<Self, AppError> {
+        let Query(params) = params.map_err(|e| {
+            AppError::bad_request()
+                .with_debug(e.to_string())
+                .at_site(site)
+        })?;
+        let format =
+            FeedFormat::from_file(file).ok_or_else(|| AppError::not_found().at_site(site))?;
+
+        Ok(Self {
+            format,
+            mature: params.mature.unwrap_or(MatureFilter::Exclude),
+            headers,
+            self_url: format!("{}{}", site_url(site), path),
+        })
+    }
+}
+
+async fn feed(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    request: FeedRequest,
+    scope: FeedScope,
+) -> Result<Response, AppError> {
+    let mut filter = PostFilter {
+        mature: request.mature,
+        ..PostFilter::default()
+    };
+    let title = match &scope {
+        FeedScope::Site => site.domain.clone(),
+        FeedScope::Tag(tag) => {
+            filter.tags = TagFilter {
+                all: vec![tag.clone()],
+                ..TagFilter::default()
+            };
+            format!("{}: posts tagged {}", site.domain, tag)
+        }
+        FeedScope::Author(uuid, name) => {
+            filter.author = Some(*uuid);
+            format!("{}: posts by {}", site.domain, name)
+        }
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new(
+        r#"SELECT
+            id, uuid, title, slug, content, created_at, updated_at, post_tag_names(id) AS tags, signature,
+            is_mature, summary, author_uuid, status, published_at,
+            (SELECT name FROM authors WHERE authors.uuid = posts.author_uuid) AS author_name"#,
+    );
+    push_post_listing(&mut query, site, None, None, false, &[filter]);
+    query
+        .push(" ORDER BY published_at DESC, uuid DESC LIMIT ")
+        .push_bind(FEED_LENGTH);
+
+    let entries = query
+        .build_query_as::<FeedEntry>()
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    // Anything that changes the feed's bytes changes its tag, dropped posts included.
+    // There's no Last-Modified, a post dropping out can leave the newest time older.
+    let mut hasher = Sha256::new();
+    hasher.update(site.highlight_class_prefix.as_bytes());
+    for entry in &entries {
+        hasher.update(entry.post.uuid.as_bytes());
+        hasher.update(entry.post.updated_at.timestamp_micros().to_be_bytes());
+    }
+    let etag = format!("\"{}\"", hex::encode(&hasher.finalize()[..16]));
+
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, value);
+    }
+
+    if is_not_modified(&request.headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let posts: Vec<(i32, &str)> = entries
+        .iter()
+        .map(|e| (e.post.id, e.post.content.as_str()))
+        .collect();
+    let rendered = rendered_content(pool, &posts, &site.highlight_class_prefix)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+    let urls = SiteUrls::load(pool, site)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    let feed = Feed {
+        title,
+        urls: &urls,
+        self_url: request.self_url,
+        updated: entries
+            .iter()
+            .map(|e| e.post.updated_at)
+            .max()
+            .unwrap_or_else(Utc::now),
+        entries: &entries,
+        rendered: &rendered,
+    };
+    let body = match request.format {
+        FeedFormat::Rss => feed.rss(),
+        FeedFormat::Atom => feed.atom(),
+        FeedFormat::Json => feed.json(),
+    };
+
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(request.format.content_type()),
+    );
+    Ok((headers, body).into_response())
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        })
+}
+
+struct Feed<'a> {
+    title: String,
+    urls: &'a SiteUrls,
+    self_url: String,
+    updated: DateTime<Utc>,
+    entries: &'a [FeedEntry],
+    rendered: &'a HashMap<i32, String>,
+}
+
+impl Feed<'_> {
+    fn link(&self, post: &Post) -> String {
+        self.urls.post(post.slug.as_deref(), post.uuid)
+    }
+
+    fn content(&self, post: &Post) -> &str {
+        self.rendered
+            .get(&post.id)
+            .map(|s| s.as_str())
+            .unwrap_or("")
+    }
+
+    fn published(post: &Post) -> DateTime<Utc> {
+        post.published_at.unwrap_or(post.created_at)
+    }
+
+    fn tags(post: &Post) -> Vec<&str> {
+        post.tags
+            .as_array()
+            .map(|tags| tags.iter().filter_map(|t| t.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    fn rss(&self) -> String {
+        let mut xml = String::new();
+        let _ = write!(
+            xml,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+                "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" ",
+                "xmlns:content=\"http://purl.org/rss/1.0/modules/content/\" ",
+                "xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+                "<channel>\n<title>{}</title>\n<link>{}</link>\n<description>{}</description>\n",
+                "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+                "<lastBuildDate>{}</lastBuildDate>\n"
+            ),
+            escape_xml(&self.title),
+            escape_xml(self.urls.base()),
+            escape_xml(&self.title),
+            escape_xml(&self.self_url),
+            self.updated.to_rfc2822()
+        );
+
+        for FeedEntry { post, author_name } in self.entries {
+            let content = self.content(post);
+            let link = self.link(post);
+            let _ = write!(
+                xml,
+                concat!(
+                    "<item>\n<title>{}</title>\n<link>{}</link>\n",
+                    "<guid isPermaLink=\"false\">urn:uuid:{}</guid>\n<pubDate>{}</pubDate>\n",
+                    "<description>{}</description>\n<content:encoded>{}</content:encoded>\n"
+                ),
+                escape_xml(&post.title),
+                escape_xml(&link),
+                post.uuid,
+                Self::published(post).to_rfc2822(),
+                escape_xml(post.summary.as_deref().unwrap_or(content)),
+                escape_xml(content)
+            );
+            if let Some(name) = author_name {
+                let _ = writeln!(xml, "<dc:creator>{}</dc:creator>", escape_xml(name));
+            }
+            for tag in Self::tags(post) {
+                let _ = writeln!(xml, "<category>{}</category>", escape_xml(tag));
+            }
+            xml.push_str("</item>\n");
+        }
+
+        xml.push_str("</channel>\n</rss>\n");
+        xml
+    }
+
+    fn atom(&self) -> String {
+        let mut xml = String::new();
+        let _ = write!(
+            xml,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+                "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+                "<title>{}</title>\n<id>{}</id>\n<updated>{}</updated>\n",
+                "<link href=\"{}\"/>\n<link href=\"{}\" rel=\"self\"/>\n",
+                "<author><name>{}</name></author>\n"
+            ),
+            escape_xml(&self.title),
+            escape_xml(&self.self_url),
+            self.updated.to_rfc3339(),
+            escape_xml(self.urls.base()),
+            escape_xml(&self.self_url),
+            escape_xml(self.urls.base())
+        );
+
+        for FeedEntry { post, author_name } in self.entries {
+            let _ = write!(
+                xml,
+                concat!(
+                    "<entry>\n<title>{}</title>\n<id>urn:uuid:{}</id>\n<link href=\"{}\"/>\n",
+                    "<published>{}</published>\n<updated>{}</updated>\n",
+                    "<content type=\"html\">{}</content>\n"
+                ),
+                escape_xml(&post.title),
+                post.uuid,
+                escape_xml(&self.link(post)),
+                Self::published(post).to_rfc3339(),
+                post.updated_at.to_rfc3339(),
+                escape_xml(self.content(post))
+            );
+            if let Some(summary) = &post.summary {
+                let _ = writeln!(xml, "<summary>{}</summary>", escape_xml(summary));
+            }
+            if let Some(name) = author_name {
+                let _ = writeln!(xml, "<author><name>{}</name></author>", escape_xml(name));
+            }
+            for tag in Self::tags(post) {
+                let _ = writeln!(xml, "<category term=\"{}\"/>", escape_xml(tag));
+            }
+            xml.push_str("</entry>\n");
+        }
+
+        xml.push_str("</feed>\n");
+        xml
+    }
+
+    fn json(&self) -> String {
+        let items: Vec<serde_json::Value> = self
+            .entries
+            .iter()
+            .map(|FeedEntry { post, author_name }| {
+                let mut item = serde_json::json!({
+                    "id": post.uuid,
+                    "url": self.link(post),
+                    "title": post.title,
+                    "content_html": self.content(post),
+                    "date_published": Self::published(post).to_rfc3339(),
+                    "date_modified": post.updated_at.to_rfc3339(),
+                    "tags": Self::tags(post),
+                });
+                if let Some(summary) = &post.summary {
+                    item["summary"] = summary.as_str().into();
+                }
+                if let Some(name) = author_name {
+                    item["authors"] = serde_json::json!([{ "name": name }]);
+                }
+                item
+            })
+            .collect();
+
+        serde_json::json!({
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": self.title,
+            "home_page_url": self.urls.base(),
+            "feed_url": self.self_url,
+            "items": items,
+        })
+        .to_string()
+    }
+}
+
+/// Escape text for XML content and attribute values
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{body::to_bytes, http::Uri};
+
+    async fn get(
+        pool: &PgPool,
+        site: &SiteIdentity,
+        file: &str,
+        if_none_match: Option<&str>,
+    ) -> Response {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = if_none_match {
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_str(etag).unwrap());
+        }
+        let uri: Uri = format!("/api/feeds/{}", file).parse().unwrap();
+        let params = Query::try_from_uri(&uri);
+
+        let Ok(response) = site_feed(
+            State(pool.clone()),
+            site.clone(),
+            headers,
+            OriginalUri(uri),
+            Path(file.to_string()),
+            params,
+        )
+        .await
+        else {
+            panic!("{} failed", file);
+        };
+        response
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_each_format() {
+        let pool = testing::pool().await;
+        let site = testing::create_site(&pool).await;
+        let (_, uuid) = testing::create_post(&pool, &site, "published", &["feeds"]).await;
+        let (_, draft) = testing::create_post(&pool, &site, "draft", &[]).await;
+
+        for (file, content_type, start) in [
+            ("rss.xml", "application/rss+xml", "<?xml"),
+            ("atom.xml", "application/atom+xml", "<?xml"),
+            ("feed.json", "application/feed+json", "{"),
+        ] {
+            let response = get(&pool, &site, file, None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(
+                response.headers()[CONTENT_TYPE]
+                    .to_str()
+                    .unwrap()
+                    .starts_with(content_type)
+            );
+            assert!(response.headers().contains_key(ETAG));
+
+            let body = body(response).await;
+            assert!(body.starts_with(start), "{}", body);
+            assert!(body.contains(&uuid.to_string()), "{}", body);
+            assert!(body.contains("feeds"), "{}", body);
+            assert!(!body.contains(&draft.to_string()), "{}", body);
+        }
+
+        let Err(error) = site_feed(
+            State(pool.clone()),
+            site.clone(),
+            HeaderMap::new(),
+            OriginalUri(Uri::from_static("/api/feeds/feed.txt")),
+            Path("feed.txt".to_string()),
+            Ok(Query(FeedParams { mature: None })),
+        )
+        .await
+        else {
+            panic!("an unknown format was served");
+        };
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+
+        testing::delete_site(&pool, &site).await;
+    }
+
+    #[tokio::test]
+    async fn answers_conditional_requests() {
+        let pool = testing::pool().await;
+        let site = testing::create_site(&pool).await;
+        let (first, _) = testing::create_post(&pool, &site, "published", &[]).await;
+
+        let response = get(&pool, &site, "atom.xml", None).await;
+        assert!(!response.headers().contains_key("last-modified"));
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+
+        let unchanged = get(&pool, &site, "atom.xml", Some(&etag)).await;
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(unchanged.headers()[ETAG], etag.as_str());
+        assert!(body(unchanged).await.is_empty());
+
+        let weak = format!("\"other\", W/{}", etag);
+        let unchanged = get(&pool, &site, "atom.xml", Some(&weak)).await;
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+
+        let stale = get(&pool, &site, "atom.xml", Some("\"other\"")).await;
+        assert_eq!(stale.status(), StatusCode::OK);
+
+        // A new post changes the tag
+        testing::create_post(&pool, &site, "published", &[]).await;
+        let changed = get(&pool, &site, "atom.xml", Some(&etag)).await;
+        assert_eq!(changed.status(), StatusCode::OK);
+        let newer = changed.headers()[ETAG].to_str().unwrap().to_string();
+        assert_ne!(newer, etag);
+
+        // So does one dropping out, even though nothing left in the feed changed
+        sqlx::query!("DELETE FROM posts WHERE id = $1", first)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let changed = get(&pool, &site, "atom.xml", Some(&newer)).await;
+        assert_eq!(changed.status(), StatusCode::OK);
+
+        testing::delete_site(&pool, &site).await;
+    }
+}
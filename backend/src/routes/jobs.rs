@@ -0,0 +1,199 @@
+use crate::{
+    auth::Role,
+    config::AppConfig,
+    error::{AppError, FieldError},
+    extractors::{Actor, SiteIdentity},
+    pagination::Listing,
+    params::PaginationParams,
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const STATUSES: [&str; 4] = ["queued", "running", "done", "dead"];
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct JobResponse {
+    pub uuid: Uuid,
+    pub kind: String,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+const JOB_SELECT: &str = "
+    SELECT
+        uuid, kind, payload, status, attempts, max_attempts, run_at, last_error, created_at, finished_at
+    FROM jobs";
+
+#[derive(Deserialize, Debug)]
+pub struct JobParams {
+    #[serde(flatten)]
+    pub page: PaginationParams,
+    pub status: Option<String>,
+    pub kind: Option<String>,
+}
+
+/// Jobs newest first, optionally only those with a status or kind
+pub async fn get_jobs(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Query(params): Query<JobParams>,
+) -> Result<Listing<JobResponse>, AppError> {
+    // Jobs aren't kept per site, so only an owner of every site can see or touch them
+    actor.require_everywhere(&pool, Role::Owner).await?;
+
+    let bounds = params
+        .page
+        .bounds(
+            config.pagination.default_limit,
+            config.pagination.max_limit("jobs"),
+        )
+        .map_err(|e| e.at_site(&site))?;
+
+    if let Some(status) = &params.status
+        && !STATUSES.contains(&status.as_str())
+    {
+        return Err(AppError::bad_request()
+            .with_message("Invalid query parameters")
+            .with_fields(vec![FieldError::new(
+                "status",
+                "Must be queued, running, done or dead",
+            )])
+            .at_site(&site));
+    }
+
+    let query = format!(
+        "{} WHERE ($1::TEXT IS NULL OR status = $1) AND ($2::TEXT IS NULL OR kind = $2)
+        ORDER BY created_at DESC, id DESC LIMIT $3 OFFSET $4",
+        JOB_SELECT
+    );
+    let jobs = sqlx::query_as::<_, JobResponse>(&query)
+        .bind(&params.status)
+        .bind(&params.kind)
+        .bind(bounds.limit)
+        .bind(bounds.offset)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let total = if bounds.with_total {
+        let count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!" FROM jobs
+            WHERE ($1::TEXT IS NULL OR status = $1) AND ($2::TEXT IS NULL OR kind = $2)
+            "#,
+            params.status,
+            params.kind
+        )
+        .fetch_one(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Listing::Plain { items: jobs, total })
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct JobStats {
+    pub kind: String,
+    pub status: String,
+    pub count: i64,
+    /// When the oldest job in the group was queued, to spot a backlog
+    pub oldest: DateTime<Utc>,
+}
+
+/// How many jobs of each kind are in each status
+pub async fn get_job_stats(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+) -> Result<Json<Vec<JobStats>>, AppError> {
+    actor.require_everywhere(&pool, Role::Owner).await?;
+
+    let stats = sqlx::query_as::<_, JobStats>(
+        r#"
+        SELECT kind, status, COUNT(*) AS count, MIN(created_at) AS oldest
+        FROM jobs
+        GROUP BY kind, status
+        ORDER BY kind, status
+        "#,
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(stats))
+}
+
+pub async fn get_job(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+) -> Result<Json<JobResponse>, AppError> {
+    actor.require_everywhere(&pool, Role::Owner).await?;
+
+    let query = format!("{} WHERE uuid = $1", JOB_SELECT);
+    let job = sqlx::query_as::<_, JobResponse>(&query)
+        .bind(uuid)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    Ok(Json(job))
+}
+
+/// Put a dead job back on the queue with a fresh set of attempts
+pub async fn retry_job(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+) -> Result<StatusCode, AppError> {
+    actor.require_everywhere(&pool, Role::Owner).await?;
+
+    let status = sqlx::query_scalar!("SELECT status FROM jobs WHERE uuid = $1", uuid)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    if status != "dead" {
+        return Err(AppError::conflict()
+            .with_message("Only dead jobs can be retried")
+            .at_site(&site));
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE jobs
+        SET status = 'queued', attempts = 0, run_at = NOW(), finished_at = NULL
+        WHERE uuid = $1 AND status = 'dead'
+        "#,
+        uuid
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(StatusCode::ACCEPTED)
+}
@@ -1,12 +1,21 @@
 pub mod authors;
+pub mod feeds;
+pub mod jobs;
 pub mod posts;
+pub mod related;
+pub mod revisions;
+pub mod search;
+pub mod sessions;
+pub mod sitemaps;
 pub mod sites;
 pub mod tags;
+pub mod tokens;
+pub mod webhooks;
 
 use crate::AppState;
 use axum::{
     Router,
-    routing::{get, post, delete},
+    routing::{get, post, put, delete},
 };
 
 pub fn create_router(state: AppState) -> Router {
@@ -15,30 +24,74 @@ pub fn create_router(state: AppState) -> Router {
         .nest("/api/tags", tag_routes())
         .nest("/api/sites", site_routes())
         .nest("/api/authors", author_routes())
+        .nest("/api/tokens", token_routes())
+        .nest("/api/auth", auth_routes())
+        .nest("/api/search", search_routes())
+        .nest("/api/webhooks", webhook_routes())
+        .nest("/api/jobs", job_routes())
+        .nest("/feeds", feed_routes())
+        .route("/sitemap.xml", get(sitemaps::sitemap))
+        .route("/sitemaps/{file}", get(sitemaps::sitemap_page))
+        .route("/robots.txt", get(sitemaps::robots_txt))
         .with_state(state)
 }
 
 pub fn post_routes() -> Router<AppState> {
     Router::new()
         .route("/", get(posts::get_posts).post(posts::create_post))
+        .route("/admin", get(posts::admin_get_posts))
+        .route("/filter", post(posts::filter_posts))
+        .route("/admin/filter", post(posts::admin_filter_posts))
         .route("/{id}", 
             get(posts::get_one_post)
             .put(posts::update_post)
+            .patch(posts::patch_post)
             .delete(posts::delete_post)
         )
+        .route("/{id}/related", get(related::related_posts))
+        .route("/{id}/revisions", get(revisions::get_revisions))
+        .route("/{id}/revisions/diff", get(revisions::diff_revisions))
+        .route(
+            "/{id}/revisions/{revision}/restore",
+            post(revisions::restore_revision),
+        )
+}
+
+pub fn feed_routes() -> Router<AppState> {
+    Router::new()
+        .route("/{file}", get(feeds::site_feed))
+        .route("/tags/{tag}/{file}", get(feeds::tag_feed))
+        .route("/authors/{uuid}/{file}", get(feeds::author_feed))
 }
 
 pub fn tag_routes() -> Router<AppState> {
     Router::new()
         .route("/", get(tags::fetch_tags))
         .route("/admin", get(tags::admin_fetch_tags))
-        .route("/{uuid}", post(tags::increment_tag_selection))
+        .route("/admin/recompute", post(tags::recompute_tags))
+        .route(
+            "/{uuid}",
+            get(tags::get_tag).post(tags::increment_tag_selection),
+        )
+        .route("/{uuid}/related", get(related::related_tags))
+        .route("/{uuid}/rename", post(tags::rename_tag))
+        .route("/{uuid}/merge", post(tags::merge_tag))
+        .route("/{uuid}/parent", put(tags::set_tag_parent))
+        .route("/{uuid}/details", put(tags::set_tag_details))
+        .route("/{uuid}/aliases", post(tags::add_tag_alias))
+        .route("/{uuid}/aliases/{alias}", delete(tags::delete_tag_alias))
 }
 
 pub fn site_routes() -> Router<AppState> {
     Router::new()
         .route("/", get(sites::get_sites).post(sites::create_site))
-        .route("/{id}", delete(sites::delete_site))
+        .route("/highlight.css", get(sites::highlight_css))
+        .route("/{id}", delete(sites::delete_site).patch(sites::update_site))
+        .route("/{id}/roles", get(sites::get_roles))
+        .route(
+            "/{id}/roles/{author_uuid}",
+            put(sites::grant_role).delete(sites::revoke_role),
+        )
 }
 
 pub fn author_routes() -> Router<AppState> {
@@ -46,3 +99,39 @@ pub fn author_routes() -> Router<AppState> {
         .route("/", get(authors::get_authors).post(authors::create_author))
         .route("/{uuid}/socials", post(authors::add_social))
 }
+
+pub fn token_routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(tokens::get_tokens).post(tokens::create_token))
+        .route("/{uuid}", delete(tokens::revoke_token))
+}
+
+pub fn webhook_routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(webhooks::get_webhooks).post(webhooks::create_webhook))
+        .route("/{uuid}", delete(webhooks::delete_webhook))
+        .route("/{uuid}/deliveries", get(webhooks::get_deliveries))
+        .route(
+            "/{uuid}/deliveries/{delivery}/retry",
+            post(webhooks::retry_delivery),
+        )
+}
+
+pub fn job_routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(jobs::get_jobs))
+        .route("/stats", get(jobs::get_job_stats))
+        .route("/{uuid}", get(jobs::get_job))
+        .route("/{uuid}/retry", post(jobs::retry_job))
+}
+
+pub fn auth_routes() -> Router<AppState> {
+    Router::new()
+        .route("/oidc/login", get(sessions::oidc_login))
+        .route("/oidc/callback", get(sessions::oidc_callback))
+        .route("/logout", post(sessions::logout))
+}
+
+pub fn search_routes() -> Router<AppState> {
+    Router::new().route("/suggest", get(search::suggest))
+}
@@ -1,27 +1,45 @@
 use crate::{
-    error::AppError, extractors::SiteIdentity, gpg::GpgVerifier, models::Post, params::SearchParams,
+    auth::Role,
+    config::AppConfig,
+    error::AppError,
+    extractors::{Actor, IfMatch, SiteIdentity},
+    filters::{PostFilter, PostFilterQuery, TagFilter},
+    gpg::GpgVerifier,
+    models::{Post, PostStatus},
+    pagination::{Keyset, Listing, PageRequest},
+    params::{PageBounds, SearchParams, SortDirection},
+    publishing::{on_published, resolve_status, schedule_publish},
+    render::{SNIPPET_START, SNIPPET_STOP, cache_render, render_markdown, render_snippet, rendered_content},
+    visibility::Visibility,
+    webhooks::{self, WebhookEvent},
 };
 use axum::{
     Json,
-    extract::{Path, Query, State, rejection::QueryRejection},
-    http::StatusCode,
+    extract::{OriginalUri, Path, Query, State, rejection::QueryRejection},
+    http::{StatusCode, Uri, header::ETAG},
+    response::IntoResponse,
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool, Postgres, QueryBuilder};
 
 #[derive(Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum PostSort {
     Date,
     Title,
+    /// Best matches for `search` first, falls back to date without a search
+    Relevance,
 }
 
 #[derive(serde::Deserialize)]
 pub struct PostParams {
     #[serde(flatten)]
     base: SearchParams<PostSort>,
-    tag: Option<String>,
+    #[serde(flatten)]
+    filter: PostFilterQuery,
+    /// Only used by the admin listing, the public only ever sees published posts
+    status: Option<PostStatus>,
 }
 
 impl PostParams {}
@@ -31,6 +49,8 @@ pub struct PostResponse {
     slug: Option<String>,
     title: String,
     content: String,
+    /// `content` rendered and sanitized
+    content_html: String,
     created: DateTime<Utc>,
     updated: DateTime<Utc>,
     tags: serde_json::Value,
@@ -38,15 +58,21 @@ pub struct PostResponse {
     is_mature: bool,
     summary: Option<String>,
     author_uuid: Option<uuid::Uuid>,
+    status: PostStatus,
+    published_at: Option<DateTime<Utc>>,
+    /// The parts of the post matching a search, with matches wrapped in `<mark>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
 }
 
-impl From<Post> for PostResponse {
-    fn from(post: Post) -> Self {
+impl PostResponse {
+    fn new(post: Post, content_html: String) -> Self {
         Self {
             uuid: post.uuid,
             slug: post.slug,
             title: post.title,
             content: post.content,
+            content_html,
             created: post.created_at,
             updated: post.updated_at,
             tags: post.tags,
@@ -54,15 +80,57 @@ impl From<Post> for PostResponse {
             is_mature: post.is_mature,
             summary: post.summary,
             author_uuid: post.author_uuid,
+            status: post.status,
+            published_at: post.published_at,
+            snippet: post.snippet.as_deref().map(render_snippet),
         }
     }
 }
 
+/// Build responses for posts, using the cached HTML for each one's latest revision
+pub async fn post_responses(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    posts: Vec<Post>,
+) -> Result<Vec<PostResponse>, AppError> {
+    let contents: Vec<(i32, &str)> = posts.iter().map(|p| (p.id, p.content.as_str())).collect();
+    let mut rendered = rendered_content(pool, &contents, &site.highlight_class_prefix)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    Ok(posts
+        .into_iter()
+        .map(|post| {
+            let html = rendered
+                .remove(&post.id)
+                .unwrap_or_else(|| render_markdown(&post.content, &site.highlight_class_prefix));
+            PostResponse::new(post, html)
+        })
+        .collect())
+}
+
+/// A single post's response along with its ETag header
+pub async fn post_response(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    post: Post,
+) -> Result<impl IntoResponse + use<>, AppError> {
+    let etag = post_etag(post.updated_at);
+    let response = post_responses(pool, site, vec![post]).await?.remove(0);
+
+    Ok(([(ETAG, etag)], Json(response)))
+}
+
+/// A post's ETag, which changes every time the post is saved
+pub fn post_etag(updated_at: DateTime<Utc>) -> String {
+    format!("\"{}\"", updated_at.timestamp_micros())
+}
+
 pub async fn get_one_post(
     State(pool): State<PgPool>,
     site: SiteIdentity,
     Path(identifier): Path<String>,
-) -> Result<Json<PostResponse>, AppError> {
+) -> Result<impl IntoResponse, AppError> {
     let query = if let Ok(id) = uuid::Uuid::parse_str(&identifier) {
         sqlx::query_as::<_, Post>(
             "SELECT 
@@ -73,21 +141,25 @@ pub async fn get_one_post(
                 content, 
                 created_at, 
                 updated_at,
-                tags,
+                post_tag_names(id) AS tags,
                 signature,
                 is_mature,
                 summary,
-                author_uuid
+                author_uuid,
+                status,
+                published_at
             FROM 
                 posts
             WHERE
                 uuid = $1
             AND 
-                (visibility_mask & $2) > 0
+                id IN (SELECT post_id FROM post_sites WHERE site_id = $2)
+            AND
+                status = 'published' AND published_at <= NOW()
             ",
         )
         .bind(id)
-        .bind(site.mask)
+        .bind(site.id)
     } else {
         sqlx::query_as::<_, Post>(
             "SELECT 
@@ -98,21 +170,25 @@ pub async fn get_one_post(
                 content, 
                 created_at, 
                 updated_at,
-                tags,
+                post_tag_names(id) AS tags,
                 signature,
                 is_mature,
                 summary,
-                author_uuid
+                author_uuid,
+                status,
+                published_at
             FROM 
                 posts
             WHERE
                 slug = $1
             AND 
-                (visibility_mask & $2) > 0
+                id IN (SELECT post_id FROM post_sites WHERE site_id = $2)
+            AND
+                status = 'published' AND published_at <= NOW()
             ",
         )
         .bind(identifier)
-        .bind(site.mask)
+        .bind(site.id)
     };
     let post = query
         .fetch_optional(&pool)
@@ -120,32 +196,202 @@ pub async fn get_one_post(
         .map_err(|e| AppError::from(e).at_site(&site))?
         .ok_or_else(|| AppError::not_found().at_site(&site))?;
 
-    Ok(Json(post.into()))
+    post_response(&pool, &site, post).await
 }
 
 pub async fn get_posts(
     State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    OriginalUri(uri): OriginalUri,
+    params: Result<Query<PostParams>, QueryRejection>,
+) -> Result<Listing<PostResponse>, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let (bounds, filter) = check_listing(&params, &config, "posts", &site)?;
+    list_posts(&pool, &site, uri, &params, bounds, &[filter], false).await
+}
+
+/// Every post on the site whatever its status, optionally filtered by `status`
+pub async fn admin_get_posts(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    OriginalUri(uri): OriginalUri,
+    params: Result<Query<PostParams>, QueryRejection>,
+) -> Result<Listing<PostResponse>, AppError> {
+    actor.require(Role::Viewer)?;
+
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let (bounds, filter) = check_listing(&params, &config, "admin_posts", &site)?;
+    list_posts(&pool, &site, uri, &params, bounds, &[filter], true).await
+}
+
+/// Published posts matching a filter sent as JSON, on top of anything in the query string
+pub async fn filter_posts(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    OriginalUri(uri): OriginalUri,
+    params: Result<Query<PostParams>, QueryRejection>,
+    Json(body): Json<PostFilter>,
+) -> Result<Listing<PostResponse>, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let (bounds, filter) = check_listing(&params, &config, "posts", &site)?;
+    list_posts(&pool, &site, uri, &params, bounds, &[filter, body], false).await
+}
+
+/// The admin listing with a filter sent as JSON
+pub async fn admin_filter_posts(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
     site: SiteIdentity,
+    actor: Actor,
+    OriginalUri(uri): OriginalUri,
     params: Result<Query<PostParams>, QueryRejection>,
-) -> Result<Json<Vec<PostResponse>>, AppError> {
+    Json(body): Json<PostFilter>,
+) -> Result<Listing<PostResponse>, AppError> {
+    actor.require(Role::Viewer)?;
+
     let Query(params) = params.map_err(|e| {
         AppError::bad_request()
             .with_debug(e.to_string())
             .at_site(&site)
     })?;
 
-    let limit = params.base.limit();
-    let offset = params.base.offset();
-    let column = match params.base.sort() {
-        Some(PostSort::Title) => "REGEXP_REPLACE(title, '^(The|A|An)\\s+', '', 'i')",
-        _ => "created_at",
+    let (bounds, filter) = check_listing(&params, &config, "admin_posts", &site)?;
+    list_posts(&pool, &site, uri, &params, bounds, &[filter, body], true).await
+}
+
+/// Published posts with a tag, paged and filtered like the public listing
+pub async fn tag_posts(
+    pool: &PgPool,
+    config: &AppConfig,
+    site: &SiteIdentity,
+    uri: Uri,
+    params: &PostParams,
+    tag_name: &str,
+) -> Result<Listing<PostResponse>, AppError> {
+    let (bounds, filter) = check_listing(params, config, "posts", site)?;
+    let tagged = PostFilter {
+        tags: TagFilter {
+            all: vec![tag_name.to_string()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    list_posts(pool, site, uri, params, bounds, &[filter, tagged], false).await
+}
+
+// Check the paging and filter of a listing's query string, reporting every bad field at once
+fn check_listing(
+    params: &PostParams,
+    config: &AppConfig,
+    endpoint: &str,
+    site: &SiteIdentity,
+) -> Result<(PageBounds, PostFilter), AppError> {
+    let bounds = params.base.bounds(
+        config.pagination.default_limit,
+        config.pagination.max_limit(endpoint),
+    );
+
+    match (bounds, params.filter.parse()) {
+        (Ok(bounds), Ok(filter)) => Ok((bounds, filter)),
+        (Err(mut e), Err(other)) => {
+            e.fields.extend(other.fields);
+            Err(e.at_site(site))
+        }
+        (Err(e), _) | (_, Err(e)) => Err(e.at_site(site)),
+    }
+}
+
+/// The FROM and WHERE of a post listing, shared with its count and feeds
+pub fn push_post_listing(
+    query: &mut QueryBuilder<'_, Postgres>,
+    site: &SiteIdentity,
+    search: Option<&str>,
+    status: Option<PostStatus>,
+    include_unpublished: bool,
+    filters: &[PostFilter],
+) {
+    query
+        .push(" FROM posts, LATERAL (SELECT websearch_to_tsquery('english', ")
+        .push_bind(search.map(String::from))
+        .push("::TEXT) AS query) AS search")
+        .push(" WHERE id IN (SELECT post_id FROM post_sites WHERE site_id = ")
+        .push_bind(site.id)
+        .push(") AND (search.query IS NULL OR search_vector @@ search.query)");
+
+    if !include_unpublished {
+        query.push(" AND status = 'published' AND published_at <= NOW()");
+    } else if let Some(status) = status {
+        query.push(" AND status = ").push_bind(status);
+    }
+
+    for filter in filters {
+        filter.push_sql(query);
+    }
+}
+
+async fn list_posts(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    uri: Uri,
+    params: &PostParams,
+    bounds: PageBounds,
+    filters: &[PostFilter],
+    include_unpublished: bool,
+) -> Result<Listing<PostResponse>, AppError> {
+    let search = params
+        .base
+        .search()
+        .map(|s| s.as_str())
+        .filter(|s| !s.trim().is_empty());
+    let (column, key_type) = match params.base.sort() {
+        Some(PostSort::Title) => ("REGEXP_REPLACE(title, '^(The|A|An)\\s+', '', 'i')", "TEXT"),
+        Some(PostSort::Relevance) if search.is_some() => {
+            ("ts_rank_cd(search_vector, search.query)", "REAL")
+        }
+        _ => ("created_at", "TIMESTAMPTZ"),
+    };
+
+    // Relevance reads best first unless asked otherwise
+    let direction = match (params.base.sort(), params.base.sortable.sort_by) {
+        (Some(PostSort::Relevance), None) => SortDirection::Desc,
+        _ => params.base.sort_by(),
     };
 
-    let direction = params.base.sort_by().to_sql();
+    let keyset = Keyset {
+        column,
+        key_type,
+        uuid_column: "uuid",
+        direction,
+    };
+    let ordering = format!("posts:{}:{}", column, direction.to_sql());
+    let page = PageRequest::new(bounds, params.base.cursor(), &ordering, key_type)
+        .map_err(|e| e.at_site(site))?;
 
-    let search_pattern = params.base.search().map(|s| format!("%{}%", s));
+    let headline_options = format!(
+        "StartSel={}, StopSel={}, MaxFragments=2, MaxWords=30, MinWords=10",
+        SNIPPET_START, SNIPPET_STOP
+    );
 
-    let query = format!(
+    let mut query = QueryBuilder::<Postgres>::new(
         r#"SELECT 
             id, 
             uuid,
@@ -154,36 +400,72 @@ pub async fn get_posts(
             content, 
             created_at, 
             updated_at,
-            tags,
+            post_tag_names(id) AS tags,
             signature,
             is_mature,
-            summary
-        FROM 
-            posts
-        WHERE
-            (visibility_mask & $1) > 0
-        AND
-            ($4::TEXT IS NULL or tags ? $4)
-        AND
-            ($5::TEXT is NULL or title ILIKE $5)
-        ORDER BY 
-            {} {}
-        LIMIT $2 OFFSET $3"#,
-        column, direction
+            summary,
+            author_uuid,
+            status,
+            published_at,
+            CASE WHEN search.query IS NULL THEN NULL
+                ELSE ts_headline('english', content, search.query, "#,
     );
-    let posts = sqlx::query_as::<_, Post>(&query)
-        .bind(site.mask)
-        .bind(limit)
-        .bind(offset)
-        .bind(&params.tag)
-        .bind(search_pattern)
-        .fetch_all(&pool)
+    query
+        .push_bind(headline_options)
+        .push(format!(") END AS snippet, ({})::TEXT AS sort_key", column));
+    push_post_listing(
+        &mut query,
+        site,
+        search,
+        params.status,
+        include_unpublished,
+        filters,
+    );
+    keyset.push_condition(&mut query, page.backwards(), page.cursor());
+    query
+        .push(format!(" ORDER BY {} LIMIT ", keyset.order(page.backwards())))
+        .push_bind(page.fetch_limit())
+        .push(" OFFSET ")
+        .push_bind(page.offset());
+
+    let posts = query
+        .build_query_as::<Post>()
+        .fetch_all(pool)
         .await
-        .map_err(|e| AppError::from(e).at_site(&site))?;
+        .map_err(|e| AppError::from(e).at_site(site))?;
 
-    let response: Vec<PostResponse> = posts.into_iter().map(|p| p.into()).collect();
+    let total = if page.with_total() {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT COUNT(*)");
+        push_post_listing(
+            &mut query,
+            site,
+            search,
+            params.status,
+            include_unpublished,
+            filters,
+        );
+        let count = query
+            .build_query_scalar::<i64>()
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError::from(e).at_site(site))?;
+        Some(count)
+    } else {
+        None
+    };
 
-    Ok(Json(response))
+    let keys: Vec<(String, uuid::Uuid)> = posts
+        .iter()
+        .map(|p| (p.sort_key.clone().unwrap_or_default(), p.uuid))
+        .collect();
+    let rows = post_responses(pool, site, posts)
+        .await?
+        .into_iter()
+        .zip(keys)
+        .map(|(post, (key, uuid))| (post, key, uuid))
+        .collect();
+
+    Ok(Listing::new(&page, &ordering, uri, rows).with_total(total))
 }
 
 #[derive(serde::Deserialize)]
@@ -192,29 +474,47 @@ pub struct CreatePostRequest {
     pub slug: Option<String>,
     pub content: String,
     pub tags: Vec<String>,
-    pub visibility_mask: i32,
+    #[serde(flatten)]
+    pub visibility: Visibility,
     pub signature: Option<String>,
     pub is_mature: bool,
     pub summary: Option<String>,
     pub author_uuid: Option<uuid::Uuid>,
+    pub status: Option<PostStatus>,
+    pub published_at: Option<DateTime<Utc>>,
 }
 
 pub async fn create_post(
     State(pool): State<PgPool>,
     site: SiteIdentity,
+    actor: Actor,
     axum::Json(payload): axum::Json<CreatePostRequest>,
-) -> Result<Json<PostResponse>, AppError> {
-    if !site.requires_auth {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+) -> Result<impl IntoResponse, AppError> {
+    let site_ids = payload
+        .visibility
+        .resolve(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .ok_or_else(|| {
+            AppError::bad_request()
+                .with_message("Either visibility_mask or sites is required")
+                .at_site(&site)
+        })?;
+
+    // Posts made from a login session default to that author
+    let author_uuid = payload.author_uuid.or(actor.author_uuid);
+    actor.require_content(&site_ids, author_uuid, Role::Contributor)?;
+
+    let (status, published_at) = resolve_status(payload.status, payload.published_at)
+        .map_err(|e| AppError::bad_request().with_message(e).at_site(&site))?;
 
-    if !site.domain.starts_with("localhost") && !site.domain.starts_with("127.0.0.1") {
-        // TODO: JWT/Oauth stuff here
-        return Err(AppError::unauthorized().at_site(&site));
+    // Contributors write drafts, putting a post live or on the schedule takes an author
+    if status != PostStatus::Draft {
+        actor.require_content(&site_ids, author_uuid, Role::Author)?;
     }
 
     if let Some(sig) = &payload.signature {
-        let email = if let Some(author_uuid) = payload.author_uuid {
+        let email = if let Some(author_uuid) = author_uuid {
             sqlx::query("SELECT signing_email FROM authors WHERE uuid = $1")
                 .bind(author_uuid)
                 .fetch_optional(&pool)
@@ -239,31 +539,25 @@ pub async fn create_post(
     }
 
     let new_uuid = uuid::Uuid::new_v4();
-    let tags_json = serde_json::to_value(&payload.tags).map_err(|e| {
-        AppError::bad_request()
-            .with_message("Failed to parse tags")
-            .with_debug(e.to_string())
-            .at_site(&site)
-    })?;
 
     let mut tx = pool
         .begin()
         .await
         .map_err(|e| AppError::from(e).at_site(&site))?;
 
-    let post = sqlx::query_as::<_, Post>(
+    let mut post = sqlx::query_as::<_, Post>(
         r#"
             INSERT INTO posts (
                 uuid, 
                 title, 
                 slug, 
                 content, 
-                tags, 
-                visibility_mask,
                 signature,
                 is_mature,
                 summary,
-                author_uuid
+                author_uuid,
+                status,
+                published_at
             ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
             RETURNING 
             id,
@@ -273,87 +567,294 @@ pub async fn create_post(
             content,
             created_at,
             updated_at,
-            tags,
+            '[]'::JSONB AS tags,
             signature,
             is_mature,
             summary,
-            author_uuid
+            author_uuid,
+            status,
+            published_at
         "#,
     )
     .bind(new_uuid)
     .bind(&payload.title)
     .bind(&payload.slug)
     .bind(&payload.content)
-    .bind(&tags_json)
-    .bind(payload.visibility_mask)
     .bind(&payload.signature)
     .bind(payload.is_mature)
     .bind(&payload.summary)
-    .bind(payload.author_uuid)
+    .bind(author_uuid)
+    .bind(status)
+    .bind(published_at)
     .fetch_one(&mut *tx)
     .await?;
 
-    for tag_name in &payload.tags {
-        let tag_uuid = uuid::Uuid::new_v4();
-
-        sqlx::query!(
-            r#"
-            INSERT INTO tag_stats (tag_uuid, tag_name, visibility_mask, use_count)
-            VALUES ($1,$2,$3,1)
-            ON CONFLICT (tag_name)
-            DO UPDATE SET
-                use_count = tag_stats.use_count +1,
-                visibility_mask = tag_stats.visibility_mask | EXCLUDED.visibility_mask
-            "#,
-            tag_uuid,
-            tag_name,
-            payload.visibility_mask
-        )
-        .execute(&mut *tx)
+    post.tags = set_post_tags(&mut tx, post.id, &payload.tags)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    set_post_sites(&mut tx, post.id, &site_ids)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    record_revision(&mut tx, &post, actor.author_uuid)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    schedule_publish(&mut tx, &post)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    webhooks::enqueue_post(&mut tx, &post, WebhookEvent::PostCreated)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+    // The names as stored, with aliases already swapped for their tags
+    let tags: Vec<String> = serde_json::from_value(post.tags.clone()).unwrap_or_default();
+    webhooks::enqueue_tags(&mut tx, &site_ids, &tags)
         .await
         .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    if post.status == PostStatus::Published {
+        on_published(&mut tx, &post)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?;
     }
 
     tx.commit()
         .await
         .map_err(|e| AppError::from(e).at_site(&site))?;
 
-    Ok(Json(post.into()))
+    post_response(&pool, &site, post).await
+}
+
+// Show a post on the given sites, its tags follow through the post_sites triggers
+async fn set_post_sites(
+    conn: &mut PgConnection,
+    post_id: i32,
+    site_ids: &[i32],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM post_sites WHERE post_id = $1", post_id)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO post_sites (post_id, site_id) SELECT $1, UNNEST($2::INTEGER[])",
+        post_id,
+        site_ids
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// Replace a post's tags, creating any that are new, and return them as stored.
+// Aliases are swapped for their tags, and counts and site visibility are kept up by
+// the post_tags triggers.
+async fn set_post_tags(
+    conn: &mut PgConnection,
+    post_id: i32,
+    tags: &[String],
+) -> Result<serde_json::Value, sqlx::Error> {
+    let tags = sqlx::query_scalar!(r#"SELECT resolve_tag_names($1) AS "tags!""#, tags)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO tag_stats (tag_name) SELECT UNNEST($1::TEXT[]) ON CONFLICT (tag_name) DO NOTHING",
+        &tags
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM post_tags
+        WHERE post_id = $1
+        AND tag_uuid NOT IN (SELECT tag_uuid FROM tag_stats WHERE tag_name = ANY($2))
+        "#,
+        post_id,
+        &tags
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO post_tags (post_id, tag_uuid, position)
+        SELECT $1, t.tag_uuid, MIN(given.position)
+        FROM UNNEST($2::TEXT[]) WITH ORDINALITY AS given(name, position)
+        JOIN tag_stats t ON t.tag_name = given.name
+        GROUP BY t.tag_uuid
+        ON CONFLICT (post_id, tag_uuid) DO UPDATE SET position = EXCLUDED.position
+        "#,
+        post_id,
+        &tags
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query_scalar!(r#"SELECT post_tag_names($1) AS "tags!""#, post_id)
+        .fetch_one(&mut *conn)
+        .await
+}
+
+// Keep a copy of the post as it now stands, numbered after the last one
+async fn record_revision(
+    conn: &mut PgConnection,
+    post: &Post,
+    edited_by: Option<uuid::Uuid>,
+) -> Result<(), sqlx::Error> {
+    let revision = sqlx::query_scalar!(
+        r#"
+        INSERT INTO post_revisions (
+            post_id,
+            revision,
+            title,
+            slug,
+            content,
+            tags,
+            signature,
+            is_mature,
+            summary,
+            author_uuid,
+            edited_by
+        )
+        SELECT $1, COALESCE(MAX(revision), 0) + 1, $2, $3, $4, $5, $6, $7, $8, $9, $10
+        FROM post_revisions
+        WHERE post_id = $1
+        RETURNING revision
+        "#,
+        post.id,
+        post.title,
+        post.slug,
+        post.content,
+        post.tags,
+        post.signature,
+        post.is_mature,
+        post.summary,
+        post.author_uuid,
+        edited_by
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    // Render now for every site the post is on so readers never have to
+    let prefixes = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT s.highlight_class_prefix
+        FROM sites s
+        JOIN post_sites ps ON ps.site_id = s.id
+        WHERE ps.post_id = $1
+        "#,
+        post.id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for prefix in prefixes {
+        let html = render_markdown(&post.content, &prefix);
+        cache_render(conn, post.id, revision, &prefix, &html).await?;
+    }
+
+    Ok(())
+}
+
+/// Where a post is shown and who wrote it, for access checks
+pub async fn post_owner(
+    pool: &PgPool,
+    uuid: uuid::Uuid,
+    site: &SiteIdentity,
+) -> Result<(Vec<i32>, Option<uuid::Uuid>), AppError> {
+    let post = sqlx::query!(
+        r#"
+        SELECT
+            ARRAY(SELECT site_id FROM post_sites WHERE post_id = posts.id) AS "sites!",
+            author_uuid
+        FROM posts
+        WHERE uuid = $1
+        "#,
+        uuid
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?
+    .ok_or_else(|| AppError::not_found().at_site(site))?;
+
+    Ok((post.sites, post.author_uuid))
 }
 
 pub async fn delete_post(
     State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    if_match: IfMatch,
     Path(identifier): Path<String>,
 ) -> Result<axum::http::StatusCode, AppError> {
-    // We don't have SiteIdentity here, but we can still return a clean error
-    let uuid = uuid::Uuid::parse_str(&identifier)
-        .map_err(|e| AppError::bad_request().with_debug(e.to_string()))?;
+    let uuid = uuid::Uuid::parse_str(&identifier).map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let (site_ids, author_uuid) = post_owner(&pool, uuid, &site).await?;
+    actor.require_content(&site_ids, author_uuid, Role::Author)?;
+
     let mut tx = pool.begin().await?;
 
-    sqlx::query!(
+    // Lock the post so nothing can change it between the check and the delete
+    let updated_at = sqlx::query_scalar!(
+        r#"SELECT updated_at AS "updated_at!" FROM posts WHERE uuid = $1 FOR UPDATE"#,
+        uuid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::not_found().at_site(&site))?;
+    if_match
+        .check(&post_etag(updated_at))
+        .map_err(|e| e.at_site(&site))?;
+
+    // Queued while the post and its sites are still there to describe
+    let post = sqlx::query_as::<_, Post>(
         r#"
-        UPDATE tag_stats
-        SET use_count = use_count - 1
-        WHERE tag_name IN (
-            SELECT jsonb_array_elements_text(tags) FROM posts where uuid = $1
-        )
+        SELECT
+            id,
+            uuid,
+            title,
+            slug,
+            content,
+            created_at,
+            updated_at,
+            post_tag_names(id) AS tags,
+            signature,
+            is_mature,
+            summary,
+            author_uuid,
+            status,
+            published_at
+        FROM posts
+        WHERE uuid = $1
         "#,
-        uuid
     )
-    .execute(&mut *tx)
+    .bind(uuid)
+    .fetch_one(&mut *tx)
     .await?;
+    webhooks::enqueue_post(&mut tx, &post, WebhookEvent::PostDeleted).await?;
 
     sqlx::query!(
         r#"
         DELETE FROM posts
         WHERE uuid = $1
-        
         "#,
         uuid
     )
     .execute(&mut *tx)
     .await?;
 
+    // After the delete, so receivers see the counts without the post
+    let tags: Vec<String> = serde_json::from_value(post.tags).unwrap_or_default();
+    webhooks::enqueue_tags(&mut tx, &site_ids, &tags).await?;
+
     tx.commit().await?;
 
     Ok(StatusCode::NO_CONTENT)
@@ -364,30 +865,176 @@ pub struct UpdatePostRequest {
     pub slug: Option<String>,
     pub content: String,
     pub tags: Vec<String>,
-    pub visibility_mask: i32,
+    #[serde(flatten)]
+    pub visibility: Visibility,
     pub signature: Option<String>,
     pub is_mature: bool,
     pub summary: Option<String>,
     pub author_uuid: Option<uuid::Uuid>,
+    pub status: Option<PostStatus>,
+    pub published_at: Option<DateTime<Utc>>,
 }
 
 pub async fn update_post(
     State(pool): State<PgPool>,
     site: SiteIdentity,
+    actor: Actor,
+    if_match: IfMatch,
     Path(identifier): Path<String>,
     Json(payload): Json<UpdatePostRequest>,
-) -> Result<Json<PostResponse>, AppError> {
+) -> Result<impl IntoResponse, AppError> {
     let uuid = uuid::Uuid::parse_str(&identifier).map_err(|e| {
         AppError::bad_request()
             .with_debug(e.to_string())
             .at_site(&site)
     })?;
 
-    if let Some(sig) = &payload.signature {
+    let post = apply_update(&pool, &site, &actor, &if_match, uuid, payload).await?;
+
+    post_response(&pool, &site, post).await
+}
+
+/// Apply a JSON Merge Patch (RFC 7396) to a document
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let target = target
+        .as_object_mut()
+        .expect("target was just made an object");
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// Change only the fields that are sent, with JSON Merge Patch semantics.
+/// `null` clears a field, tags and sites are replaced as a whole.
+pub async fn patch_post(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    if_match: IfMatch,
+    Path(identifier): Path<String>,
+    Json(patch): Json<serde_json::Value>,
+) -> Result<impl IntoResponse, AppError> {
+    let uuid = uuid::Uuid::parse_str(&identifier).map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let current = sqlx::query!(
+        r#"
+        SELECT
+            title,
+            slug,
+            content,
+            post_tag_names(id) AS tags,
+            signature,
+            is_mature,
+            summary,
+            author_uuid,
+            status AS "status: PostStatus",
+            published_at,
+            updated_at AS "updated_at!",
+            ARRAY(SELECT site_id FROM post_sites WHERE post_id = posts.id) AS "sites!"
+        FROM posts
+        WHERE uuid = $1
+        AND id IN (SELECT post_id FROM post_sites WHERE site_id = $2)
+        "#,
+        uuid,
+        site.id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    // Checked before If-Match, so the post's version isn't given away to anyone who asks
+    actor.require_content(&current.sites, current.author_uuid, Role::Contributor)?;
+
+    let etag = post_etag(current.updated_at);
+    if_match.check(&etag).map_err(|e| e.at_site(&site))?;
+
+    let mut document = serde_json::json!({
+        "title": current.title,
+        "slug": current.slug,
+        "content": current.content,
+        "tags": current.tags.unwrap_or_default(),
+        "signature": current.signature,
+        "is_mature": current.is_mature,
+        "summary": current.summary,
+        "author_uuid": current.author_uuid,
+        "status": current.status,
+        "published_at": current.published_at,
+    });
+    // A legacy mask in the patch stands in for the site list rather than adding to it
+    if patch.get("visibility_mask").is_none() {
+        document["sites"] = serde_json::json!(current.sites);
+    }
+    merge_patch(&mut document, &patch);
+
+    let payload: UpdatePostRequest = serde_json::from_value(document).map_err(|e| {
+        AppError::bad_request()
+            .with_message("The patched post is not valid")
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    // The patch was made against this version, so the update must be too
+    let post = apply_update(&pool, &site, &actor, &IfMatch::exactly(etag), uuid, payload).await?;
+
+    post_response(&pool, &site, post).await
+}
+
+/// Replace a post with a new version, checking access and signatures and keeping
+/// tag_stats, site membership and revision history in step
+pub async fn apply_update(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    actor: &Actor,
+    if_match: &IfMatch,
+    uuid: uuid::Uuid,
+    payload: UpdatePostRequest,
+) -> Result<Post, AppError> {
+    // Must be allowed to edit the post as it is and as it will be
+    let (old_site_ids, author_uuid) = post_owner(pool, uuid, site).await?;
+    let site_ids = payload
+        .visibility
+        .resolve(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?
+        .unwrap_or_else(|| old_site_ids.clone());
+    actor.require_content(&old_site_ids, author_uuid, Role::Contributor)?;
+    actor.require_content(&site_ids, payload.author_uuid, Role::Contributor)?;
+
+    // What's stored was verified when it was saved, only check again if the signed parts change
+    let stored = sqlx::query!(
+        "SELECT content, signature FROM posts WHERE uuid = $1",
+        uuid
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?;
+    let signed_changed = stored.content != payload.content
+        || stored.signature != payload.signature
+        || author_uuid != payload.author_uuid;
+
+    if signed_changed && let Some(sig) = &payload.signature {
         let email = if let Some(author_uuid) = payload.author_uuid {
             sqlx::query("SELECT signing_email FROM authors WHERE uuid = $1")
                 .bind(author_uuid)
-                .fetch_optional(&pool)
+                .fetch_optional(pool)
                 .await?
                 .and_then(|r| {
                     use sqlx::Row;
@@ -403,82 +1050,61 @@ pub async fn update_post(
                 AppError::bad_request()
                     .with_message("GPG verification failed")
                     .with_debug(e.to_string())
-                    .at_site(&site)
+                    .at_site(site)
             })?;
         }
     }
 
-    if !site.requires_auth {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
-    if !site.domain.starts_with("localhost") && !site.domain.starts_with("127.0.0.1") {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
-
     let mut tx = pool
         .begin()
         .await
-        .map_err(|e| AppError::from(e).at_site(&site))?;
+        .map_err(|e| AppError::from(e).at_site(site))?;
 
-    let old_post = sqlx::query!("SELECT tags FROM posts WHERE uuid = $1", uuid)
-        .fetch_optional(&mut *tx)
-        .await
-        .map_err(|e| AppError::from(e).at_site(&site))?
-        .ok_or_else(|| AppError::not_found().at_site(&site))?;
+    // Locked until commit so concurrent updates can't both pass the If-Match check
+    let old_post = sqlx::query!(
+        r#"
+        SELECT post_tag_names(id) AS tags, status AS "status: PostStatus", published_at, updated_at AS "updated_at!"
+        FROM posts
+        WHERE uuid = $1
+        FOR UPDATE
+        "#,
+        uuid
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?
+    .ok_or_else(|| AppError::not_found().at_site(site))?;
+
+    if_match
+        .check(&post_etag(old_post.updated_at))
+        .map_err(|e| e.at_site(site))?;
+
+    // Leaving the status out keeps the post where it is in its lifecycle
+    let (status, published_at) = if payload.status.is_none() && payload.published_at.is_none() {
+        (old_post.status, old_post.published_at)
+    } else {
+        resolve_status(
+            payload.status,
+            payload.published_at.or(old_post.published_at),
+        )
+        .map_err(|e| AppError::bad_request().with_message(e).at_site(site))?
+    };
+
+    // Contributors can keep working on drafts, anything past that takes an author
+    if old_post.status != PostStatus::Draft || status != PostStatus::Draft {
+        actor.require_content(&old_site_ids, author_uuid, Role::Author)?;
+        actor.require_content(&site_ids, payload.author_uuid, Role::Author)?;
+    }
 
     let old_tags: Vec<String> =
         serde_json::from_value(old_post.tags.unwrap_or_default()).map_err(|e| {
             AppError::bad_request()
                 .with_message("Failed to parse old tags")
                 .with_debug(e.to_string())
-                .at_site(&site)
+                .at_site(site)
         })?;
 
-    let old_tags_set: std::collections::HashSet<_> = old_tags.iter().collect();
-    let new_tags_set: std::collections::HashSet<_> = payload.tags.iter().collect();
-
-    let tags_to_remove: Vec<_> = old_tags_set.difference(&new_tags_set).collect();
-    let tags_to_add: Vec<_> = new_tags_set.difference(&old_tags_set).collect();
-
-    for tag_name in tags_to_remove {
-        sqlx::query!(
-            r#"
-                UPDATE tag_stats SET use_count = use_count - 1 where tag_name = $1
-            "#,
-            tag_name
-        )
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| AppError::from(e).at_site(&site))?;
-    }
-    for tag_name in tags_to_add {
-        let tag_uuid = uuid::Uuid::new_v4();
-        sqlx::query!(
-            r#"
-                INSERT INTO tag_stats (tag_uuid, tag_name, visibility_mask, use_count)
-                VALUES ($1, $2, $3, 1)
-                ON CONFLICT (tag_name)
-                DO UPDATE SET
-                    use_count = tag_stats.use_count + 1,
-                    visibility_mask = tag_stats.visibility_mask | EXCLUDED.visibility_mask
-            "#,
-            tag_uuid,
-            tag_name,
-            payload.visibility_mask
-        )
-        .execute(&mut *tx)
-        .await
-        .map_err(|e| AppError::from(e).at_site(&site))?;
-    }
-
-    let tags_json = serde_json::to_value(&payload.tags).map_err(|e| {
-        AppError::bad_request()
-            .with_message("Failed to parse tags")
-            .with_debug(e.to_string())
-            .at_site(&site)
-    })?;
-
-    let post = sqlx::query_as::<_, Post>(
+    let mut post = sqlx::query_as::<_, Post>(
         r#"
                 UPDATE 
                     posts 
@@ -486,13 +1112,13 @@ pub async fn update_post(
                     title = $1,
                     slug = $2,
                     content = $3,
-                    tags = $4,
-                    visibility_mask = $5,
-                    signature = $6,
-                    is_mature = $7,
-                    summary = $8,
-                    updated_at = $9,
-                    author_uuid = $10
+                    signature = $4,
+                    is_mature = $5,
+                    summary = $6,
+                    updated_at = $7,
+                    author_uuid = $8,
+                    status = $9,
+                    published_at = $10
                 WHERE 
                     uuid = $11
                 RETURNING 
@@ -503,31 +1129,134 @@ pub async fn update_post(
                     content,
                     created_at,
                     updated_at,
-                    tags,
+                    '[]'::JSONB AS tags,
                     signature,
                     is_mature,
                     summary,
-                    author_uuid
+                    author_uuid,
+                    status,
+                    published_at
             "#,
     )
     .bind(&payload.title)
     .bind(&payload.slug)
     .bind(&payload.content)
-    .bind(&tags_json)
-    .bind(payload.visibility_mask)
     .bind(&payload.signature)
     .bind(payload.is_mature)
     .bind(&payload.summary)
     .bind(Utc::now())
     .bind(payload.author_uuid)
+    .bind(status)
+    .bind(published_at)
     .bind(uuid)
     .fetch_one(&mut *tx)
     .await
-    .map_err(|e| AppError::from(e).at_site(&site))?;
+    .map_err(|e| AppError::from(e).at_site(site))?;
+
+    post.tags = set_post_tags(&mut tx, post.id, &payload.tags)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    // Compared as stored, so an alias for a tag the post already had isn't a change
+    let new_tags: Vec<String> = serde_json::from_value(post.tags.clone()).unwrap_or_default();
+    let old_tags_set: std::collections::HashSet<_> = old_tags.iter().collect();
+    let new_tags_set: std::collections::HashSet<_> = new_tags.iter().collect();
+
+    let tags_to_remove: Vec<_> = old_tags_set.difference(&new_tags_set).collect();
+    let tags_to_add: Vec<_> = new_tags_set.difference(&old_tags_set).collect();
+    let changed_tags: Vec<String> = tags_to_remove
+        .iter()
+        .chain(&tags_to_add)
+        .map(|tag| tag.to_string())
+        .collect();
+
+    set_post_sites(&mut tx, post.id, &site_ids)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    record_revision(&mut tx, &post, actor.author_uuid)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    if post.published_at != old_post.published_at || post.status != old_post.status {
+        schedule_publish(&mut tx, &post)
+            .await
+            .map_err(|e| AppError::from(e).at_site(site))?;
+    }
+
+    // Sites the post was taken off hear about it too
+    let mut affected_sites = old_site_ids;
+    for id in &site_ids {
+        if !affected_sites.contains(id) {
+            affected_sites.push(*id);
+        }
+    }
+    webhooks::enqueue(
+        &mut tx,
+        Some(&affected_sites),
+        WebhookEvent::PostUpdated,
+        webhooks::post_data(&post),
+    )
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?;
+    webhooks::enqueue_tags(&mut tx, &affected_sites, &changed_tags)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    if post.status == PostStatus::Published && old_post.status != PostStatus::Published {
+        on_published(&mut tx, &post)
+            .await
+            .map_err(|e| AppError::from(e).at_site(site))?;
+    }
 
     tx.commit()
         .await
-        .map_err(|e| AppError::from(e).at_site(&site))?;
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    Ok(post)
+}
 
-    Ok(Json(post.into()))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_patch_changes_only_what_is_sent() {
+        let mut post = json!({ "title": "Old", "summary": "Kept", "is_mature": false });
+        merge_patch(&mut post, &json!({ "title": "New" }));
+        assert_eq!(
+            post,
+            json!({ "title": "New", "summary": "Kept", "is_mature": false })
+        );
+    }
+
+    #[test]
+    fn merge_patch_null_clears_a_field() {
+        let mut post = json!({ "title": "Post", "summary": "Gone soon" });
+        merge_patch(&mut post, &json!({ "summary": null }));
+        assert_eq!(post, json!({ "title": "Post" }));
+
+        // Clearing what isn't there is fine
+        merge_patch(&mut post, &json!({ "signature": null }));
+        assert_eq!(post, json!({ "title": "Post" }));
+    }
+
+    #[test]
+    fn merge_patch_replaces_arrays_whole() {
+        let mut post = json!({ "tags": ["rust", "web"], "sites": [1, 2] });
+        merge_patch(&mut post, &json!({ "tags": ["axum"], "sites": [] }));
+        assert_eq!(post, json!({ "tags": ["axum"], "sites": [] }));
+    }
+
+    #[test]
+    fn merge_patch_merges_nested_objects() {
+        let mut document = json!({ "meta": { "a": 1, "b": 2 } });
+        merge_patch(&mut document, &json!({ "meta": { "b": null, "c": 3 } }));
+        assert_eq!(document, json!({ "meta": { "a": 1, "c": 3 } }));
+
+        // A patch that isn't an object replaces the whole document
+        merge_patch(&mut document, &json!(["x"]));
+        assert_eq!(document, json!(["x"]));
+    }
 }
@@ -0,0 +1,253 @@
+use crate::{
+    config::AppConfig,
+    error::{AppError, FieldError},
+    extractors::SiteIdentity,
+    params::PaginationParams,
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State, rejection::QueryRejection},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Recommendations fill a sidebar, not a page. The max can be changed with the
+// "related" entry in `pagination.max_limits`.
+const DEFAULT_RELATED: i64 = 5;
+const MAX_RELATED: i64 = 25;
+
+// A related post scores 1 + ln(posts / posts with the tag) for each shared tag, plus
+// these for the same author and, fading with age, for being new
+const SAME_AUTHOR_WEIGHT: f64 = 1.0;
+const RECENCY_WEIGHT: f64 = 1.0;
+// Days until a post's recency bonus halves
+const RECENCY_HALF_LIFE_DAYS: f64 = 90.0;
+
+// Published posts on the site bound as $2, for a query to pick from
+const SITE_POSTS: &str = "site_posts AS (
+    SELECT p.id FROM posts p
+    JOIN post_sites ps ON ps.post_id = p.id AND ps.site_id = $2
+    WHERE p.status = 'published' AND p.published_at <= NOW()
+)";
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct RelatedPost {
+    pub uuid: Uuid,
+    pub slug: Option<String>,
+    pub title: String,
+    pub summary: Option<String>,
+    pub author_uuid: Option<Uuid>,
+    pub published_at: Option<DateTime<Utc>>,
+    pub tags: serde_json::Value,
+    /// The tags it has in common with the post, rarest first
+    pub shared_tags: serde_json::Value,
+    pub score: f64,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct RelatedTag {
+    pub name: String,
+    pub uuid: Uuid,
+    /// Posts on the site with both tags
+    pub posts_together: i64,
+    /// Posts with both over posts with either, from 0 to 1
+    pub score: f64,
+}
+
+fn related_limit(
+    params: Result<Query<PaginationParams>, QueryRejection>,
+    config: &AppConfig,
+    site: &SiteIdentity,
+) -> Result<i64, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(site)
+    })?;
+
+    // There's only ever the one page of recommendations
+    let unpaged = [
+        ("offset", &params.offset),
+        ("cursor", &params.cursor),
+        ("total", &params.total),
+    ];
+    let errors: Vec<FieldError> = unpaged
+        .into_iter()
+        .filter(|(_, value)| value.is_some())
+        .map(|(field, _)| FieldError::new(field, "Not supported here, only limit is"))
+        .collect();
+    if !errors.is_empty() {
+        return Err(AppError::bad_request()
+            .with_message("Invalid query parameters")
+            .with_fields(errors)
+            .at_site(site));
+    }
+
+    let max_limit = config.pagination.max_limit_or("related", MAX_RELATED);
+    params
+        .bounds(DEFAULT_RELATED, max_limit)
+        .map(|bounds| bounds.limit)
+        .map_err(|e| e.at_site(site))
+}
+
+/// Published posts most like a post, by the tags they share weighted by how rare each
+/// is on the site, with a boost for the same author and for newer posts. Mature posts
+/// are only recommended alongside other mature posts.
+pub async fn related_posts(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    Path(identifier): Path<String>,
+    params: Result<Query<PaginationParams>, QueryRejection>,
+) -> Result<Json<Vec<RelatedPost>>, AppError> {
+    let limit = related_limit(params, &config, &site)?;
+
+    // The post itself has to be one the site shows, found by uuid or slug
+    let uuid = Uuid::parse_str(&identifier).ok();
+    let post = sqlx::query!(
+        r#"
+        SELECT id, author_uuid, is_mature FROM posts
+        WHERE (uuid = $1 OR ($1 IS NULL AND slug = $2))
+        AND id IN (SELECT post_id FROM post_sites WHERE site_id = $3)
+        AND status = 'published' AND published_at <= NOW()
+        "#,
+        uuid,
+        identifier,
+        site.id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    let query = format!(
+        r#"
+        WITH {site_posts},
+        rarity AS (
+            SELECT
+                pt.tag_uuid,
+                LN((SELECT COUNT(*) FROM site_posts)::DOUBLE PRECISION / COUNT(*)) + 1 AS weight
+            FROM post_tags pt
+            JOIN site_posts sp ON sp.id = pt.post_id
+            WHERE pt.tag_uuid IN (SELECT tag_uuid FROM post_tags WHERE post_id = $1)
+            GROUP BY pt.tag_uuid
+        ),
+        candidates AS (
+            SELECT
+                pt.post_id,
+                SUM(r.weight) AS tag_score,
+                jsonb_agg(t.tag_name ORDER BY r.weight DESC, t.tag_name) AS shared_tags
+            FROM post_tags pt
+            JOIN site_posts sp ON sp.id = pt.post_id
+            JOIN rarity r ON r.tag_uuid = pt.tag_uuid
+            JOIN tag_stats t ON t.tag_uuid = pt.tag_uuid
+            WHERE pt.post_id <> $1
+            GROUP BY pt.post_id
+        )
+        SELECT
+            p.uuid, p.slug, p.title, p.summary, p.author_uuid, p.published_at,
+            post_tag_names(p.id) AS tags,
+            c.shared_tags,
+            c.tag_score
+                + CASE WHEN p.author_uuid = $3 THEN $4::DOUBLE PRECISION ELSE 0 END
+                + $5::DOUBLE PRECISION * POWER(
+                    0.5::DOUBLE PRECISION,
+                    LEAST(EXTRACT(EPOCH FROM NOW() - p.published_at) / 86400 / $6::DOUBLE PRECISION, 1000)::DOUBLE PRECISION
+                ) AS score
+        FROM candidates c
+        JOIN posts p ON p.id = c.post_id
+        WHERE NOT p.is_mature OR $8
+        ORDER BY score DESC, p.published_at DESC, p.uuid
+        LIMIT $7
+        "#,
+        site_posts = SITE_POSTS
+    );
+
+    let posts = sqlx::query_as::<_, RelatedPost>(&query)
+        .bind(post.id)
+        .bind(site.id)
+        .bind(post.author_uuid)
+        .bind(SAME_AUTHOR_WEIGHT)
+        .bind(RECENCY_WEIGHT)
+        .bind(RECENCY_HALF_LIFE_DAYS)
+        .bind(limit)
+        .bind(post.is_mature)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(posts))
+}
+
+/// Tags that turn up on the same published posts as a tag, most often together first
+pub async fn related_tags(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    Path(uuid): Path<Uuid>,
+    params: Result<Query<PaginationParams>, QueryRejection>,
+) -> Result<Json<Vec<RelatedTag>>, AppError> {
+    let limit = related_limit(params, &config, &site)?;
+
+    let tag_uuid = sqlx::query_scalar!(
+        r#"
+        SELECT tag_uuid AS "tag_uuid!" FROM published_tag_sites
+        WHERE tag_uuid = resolve_tag_uuid($1) AND site_id = $2
+        "#,
+        uuid,
+        site.id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    let query = format!(
+        r#"
+        WITH {site_posts},
+        tagged AS (
+            SELECT pt.post_id FROM post_tags pt
+            JOIN site_posts sp ON sp.id = pt.post_id
+            WHERE pt.tag_uuid = $1
+        ),
+        together AS (
+            SELECT pt.tag_uuid, COUNT(*) AS posts_together
+            FROM post_tags pt
+            JOIN tagged USING (post_id)
+            WHERE pt.tag_uuid <> $1
+            GROUP BY pt.tag_uuid
+        ),
+        tag_posts AS (
+            SELECT pt.tag_uuid, COUNT(*) AS posts
+            FROM post_tags pt
+            JOIN site_posts sp ON sp.id = pt.post_id
+            WHERE pt.tag_uuid IN (SELECT tag_uuid FROM together)
+            GROUP BY pt.tag_uuid
+        )
+        SELECT
+            t.tag_name AS name,
+            t.tag_uuid AS uuid,
+            tg.posts_together,
+            tg.posts_together::DOUBLE PRECISION
+                / ((SELECT COUNT(*) FROM tagged) + tp.posts - tg.posts_together) AS score
+        FROM together tg
+        JOIN tag_posts tp ON tp.tag_uuid = tg.tag_uuid
+        JOIN tag_stats t ON t.tag_uuid = tg.tag_uuid
+        ORDER BY score DESC, tg.posts_together DESC, t.tag_name
+        LIMIT $3
+        "#,
+        site_posts = SITE_POSTS
+    );
+
+    let tags = sqlx::query_as::<_, RelatedTag>(&query)
+        .bind(tag_uuid)
+        .bind(site.id)
+        .bind(limit)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(tags))
+}
@@ -0,0 +1,228 @@
+use crate::{
+    auth::Role,
+    error::AppError,
+    extractors::{Actor, IfMatch, SiteIdentity},
+    models::PostRevision,
+    routes::posts::{UpdatePostRequest, apply_update, post_owner, post_response},
+    visibility::Visibility,
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State, rejection::QueryRejection},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const REVISION_SELECT: &str = "
+    SELECT
+        revision, title, slug, content, tags, signature, is_mature, summary,
+        author_uuid, edited_by, created_at
+    FROM post_revisions
+    WHERE post_id = (SELECT id FROM posts WHERE uuid = $1)";
+
+#[derive(Serialize)]
+pub struct RevisionSummary {
+    pub revision: i32,
+    pub title: String,
+    pub tags: serde_json::Value,
+    pub signature: Option<String>,
+    pub author_uuid: Option<Uuid>,
+    pub edited_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<PostRevision> for RevisionSummary {
+    fn from(rev: PostRevision) -> Self {
+        Self {
+            revision: rev.revision,
+            title: rev.title,
+            tags: rev.tags,
+            signature: rev.signature,
+            author_uuid: rev.author_uuid,
+            edited_by: rev.edited_by,
+            created_at: rev.created_at,
+        }
+    }
+}
+
+fn parse_uuid(identifier: &str, site: &SiteIdentity) -> Result<Uuid, AppError> {
+    Uuid::parse_str(identifier).map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(site)
+    })
+}
+
+// Revisions can hold unpublished work, so reading them needs a role on every site the post is on
+async fn require_history_access(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    actor: &Actor,
+    uuid: Uuid,
+) -> Result<(), AppError> {
+    let (site_ids, author_uuid) = post_owner(pool, uuid, site).await?;
+    actor.require_content(&site_ids, author_uuid, Role::Contributor)
+}
+
+async fn fetch_revision(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    uuid: Uuid,
+    revision: Option<i32>,
+) -> Result<PostRevision, AppError> {
+    // Without a number, the latest revision
+    let query = format!(
+        "{} AND ($2::INTEGER IS NULL OR revision = $2) ORDER BY revision DESC LIMIT 1",
+        REVISION_SELECT
+    );
+    sqlx::query_as::<_, PostRevision>(&query)
+        .bind(uuid)
+        .bind(revision)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?
+        .ok_or_else(|| {
+            AppError::not_found()
+                .with_message("Unknown revision")
+                .at_site(site)
+        })
+}
+
+pub async fn get_revisions(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(identifier): Path<String>,
+) -> Result<Json<Vec<RevisionSummary>>, AppError> {
+    let uuid = parse_uuid(&identifier, &site)?;
+    require_history_access(&pool, &site, &actor, uuid).await?;
+
+    let query = format!("{} ORDER BY revision DESC", REVISION_SELECT);
+    let revisions = sqlx::query_as::<_, PostRevision>(&query)
+        .bind(uuid)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(revisions.into_iter().map(|r| r.into()).collect()))
+}
+
+#[derive(Deserialize)]
+pub struct DiffParams {
+    pub from: i32,
+    /// Defaults to the latest revision
+    pub to: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct DiffResponse {
+    pub from: i32,
+    pub to: i32,
+    pub diff: String,
+}
+
+// A revision laid out as text so every field shows up in a diff
+fn render_revision(rev: &PostRevision) -> String {
+    let tags = rev
+        .tags
+        .as_array()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|t| t.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+
+    format!(
+        "Title: {}\nSlug: {}\nSummary: {}\nTags: {}\nMature: {}\nAuthor: {}\n\n{}\n",
+        rev.title,
+        rev.slug.as_deref().unwrap_or(""),
+        rev.summary.as_deref().unwrap_or(""),
+        tags,
+        rev.is_mature,
+        rev.author_uuid.map(|u| u.to_string()).unwrap_or_default(),
+        rev.content
+    )
+}
+
+pub async fn diff_revisions(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(identifier): Path<String>,
+    params: Result<Query<DiffParams>, QueryRejection>,
+) -> Result<Json<DiffResponse>, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let uuid = parse_uuid(&identifier, &site)?;
+    require_history_access(&pool, &site, &actor, uuid).await?;
+
+    let from = fetch_revision(&pool, &site, uuid, Some(params.from)).await?;
+    let to = fetch_revision(&pool, &site, uuid, params.to).await?;
+
+    let old_text = render_revision(&from);
+    let new_text = render_revision(&to);
+    let diff = TextDiff::from_lines(&old_text, &new_text)
+        .unified_diff()
+        .context_radius(3)
+        .header(
+            &format!("revision {}", from.revision),
+            &format!("revision {}", to.revision),
+        )
+        .to_string();
+
+    Ok(Json(DiffResponse {
+        from: from.revision,
+        to: to.revision,
+        diff,
+    }))
+}
+
+/// Bring back an old revision. This saves it as a new revision on top of the
+/// history, going through the same checks as any other update.
+pub async fn restore_revision(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    if_match: IfMatch,
+    Path((identifier, revision)): Path<(String, i32)>,
+) -> Result<impl IntoResponse, AppError> {
+    let uuid = parse_uuid(&identifier, &site)?;
+    require_history_access(&pool, &site, &actor, uuid).await?;
+
+    let old = fetch_revision(&pool, &site, uuid, Some(revision)).await?;
+    let tags: Vec<String> = serde_json::from_value(old.tags).map_err(|e| {
+        AppError::bad_request()
+            .with_message("Failed to parse revision tags")
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    // Sites and status aren't part of the history, they stay as they are
+    let payload = UpdatePostRequest {
+        title: old.title,
+        slug: old.slug,
+        content: old.content,
+        tags,
+        visibility: Visibility::default(),
+        signature: old.signature,
+        is_mature: old.is_mature,
+        summary: old.summary,
+        author_uuid: old.author_uuid,
+        status: None,
+        published_at: None,
+    };
+
+    let post = apply_update(&pool, &site, &actor, &if_match, uuid, payload).await?;
+
+    post_response(&pool, &site, post).await
+}
@@ -0,0 +1,133 @@
+use crate::{config::AppConfig, error::AppError, extractors::SiteIdentity, params::SearchParams};
+use axum::{
+    Json,
+    extract::{Query, State, rejection::QueryRejection},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Suggestions are for a dropdown, never a full page of results. The max can be
+// changed with the "suggest" entry in `pagination.max_limits`.
+const DEFAULT_SUGGESTIONS: i64 = 10;
+const MAX_SUGGESTIONS: i64 = 25;
+
+// How alike a word in a name has to be to the search to be suggested
+const SIMILARITY_THRESHOLD: &str = "0.3";
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestSort {
+    /// Closest match first
+    Score,
+    Label,
+}
+
+#[derive(Serialize, sqlx::Type, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum SuggestionKind {
+    Post,
+    Tag,
+    Author,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Suggestion {
+    #[serde(rename = "type")]
+    pub kind: SuggestionKind,
+    pub uuid: Uuid,
+    pub label: String,
+    /// Posts only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    pub score: f32,
+}
+
+/// Suggest posts, tags and authors on this site that look like what's been typed so far
+pub async fn suggest(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    params: Result<Query<SearchParams<SuggestSort>>, QueryRejection>,
+) -> Result<Json<Vec<Suggestion>>, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    let max_limit = config.pagination.max_limit_or("suggest", MAX_SUGGESTIONS);
+    let limit = params
+        .bounds(DEFAULT_SUGGESTIONS, max_limit)
+        .map_err(|e| e.at_site(&site))?
+        .limit;
+
+    let Some(search) = params.search().map(|s| s.trim()).filter(|s| !s.is_empty()) else {
+        return Ok(Json(Vec::new()));
+    };
+
+    let order = match params.sort() {
+        Some(SuggestSort::Label) => format!("label {}", params.sort_by().to_sql()),
+        _ => "score DESC, label ASC".to_string(),
+    };
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Lower than the default so typos still match, only for this transaction
+    sqlx::query("SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)")
+        .bind(SIMILARITY_THRESHOLD)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let query = format!(
+        r#"
+        SELECT kind, uuid, label, slug, score FROM (
+            SELECT 'post'::TEXT AS kind, uuid, title AS label, slug, word_similarity($1, title) AS score
+            FROM posts
+            WHERE $1 <% title
+            AND id IN (SELECT post_id FROM post_sites WHERE site_id = $2)
+            AND status = 'published' AND published_at <= NOW()
+
+            UNION ALL
+
+            SELECT 'tag', tag_uuid, tag_name, NULL, word_similarity($1, tag_name)
+            FROM tag_stats
+            WHERE $1 <% tag_name
+            AND tag_uuid IN (SELECT tag_uuid FROM published_tag_sites WHERE site_id = $2)
+
+            UNION ALL
+
+            SELECT 'author', uuid, name, NULL, word_similarity($1, name)
+            FROM authors
+            WHERE $1 <% name
+            AND uuid IN (
+                SELECT author_uuid FROM posts
+                WHERE id IN (SELECT post_id FROM post_sites WHERE site_id = $2)
+                AND status = 'published' AND published_at <= NOW()
+            )
+        ) AS suggestions
+        ORDER BY {}
+        LIMIT $3
+        "#,
+        order
+    );
+
+    let suggestions = sqlx::query_as::<_, Suggestion>(&query)
+        .bind(search)
+        .bind(site.id)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(suggestions))
+}
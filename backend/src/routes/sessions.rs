@@ -0,0 +1,513 @@
+use crate::{
+    auth::{
+        Capability, SESSION_COOKIE, SESSION_TTL_HOURS, generate_token, hash_token, random_secret,
+    },
+    config::AppConfig,
+    error::AppError,
+    extractors::{SiteIdentity, raw_credential},
+    oidc::OidcClient,
+};
+use axum::{
+    Json,
+    extract::{Query, State, rejection::QueryRejection},
+    http::{HeaderMap, StatusCode, header::SET_COOKIE},
+    response::{IntoResponse, Redirect},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub token: String,
+    pub author_uuid: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+async fn oidc_client(config: AppConfig, site: &SiteIdentity) -> Result<OidcClient, AppError> {
+    let oidc = config.oidc.ok_or_else(|| {
+        AppError::not_found()
+            .with_message("OIDC login is not configured")
+            .at_site(site)
+    })?;
+
+    OidcClient::discover(oidc)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))
+}
+
+pub async fn oidc_login(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+) -> Result<Redirect, AppError> {
+    if !site.requires_auth {
+        return Err(AppError::unauthorized().at_site(&site));
+    }
+
+    let client = oidc_client(config, &site).await?;
+
+    let state = random_secret();
+    let nonce = random_secret();
+    let pkce_verifier = random_secret();
+
+    let url = client
+        .authorize_url(&state, &nonce, &pkce_verifier)
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Clear out logins that were never completed
+    sqlx::query!("DELETE FROM oidc_logins WHERE created_at < NOW() - INTERVAL '10 minutes'")
+        .execute(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "INSERT INTO oidc_logins (state, nonce, pkce_verifier, site_id) VALUES ($1, $2, $3, $4)",
+        state,
+        nonce,
+        pkce_verifier,
+        site.id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Redirect::to(&url))
+}
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+pub async fn oidc_callback(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    params: Result<Query<CallbackParams>, QueryRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    if let Some(error) = params.error {
+        return Err(AppError::unauthorized()
+            .with_message("The identity provider refused the login")
+            .with_debug(error)
+            .at_site(&site));
+    }
+
+    let code = params
+        .code
+        .ok_or_else(|| AppError::bad_request().at_site(&site))?;
+
+    // Pending logins are single use and only valid for a few minutes
+    let login = sqlx::query!(
+        r#"
+        DELETE FROM oidc_logins
+        WHERE state = $1
+        AND created_at > NOW() - INTERVAL '10 minutes'
+        RETURNING nonce, pkce_verifier, site_id
+        "#,
+        params.state
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .ok_or_else(|| {
+        AppError::unauthorized()
+            .with_message("Unknown or expired login")
+            .at_site(&site)
+    })?;
+
+    // Finished on the site it was started from, so a login can't be carried to another
+    if login.site_id != site.id {
+        return Err(AppError::unauthorized()
+            .with_message("Login was started on another site")
+            .at_site(&site));
+    }
+
+    let client = oidc_client(config, &site).await?;
+    let claims = client
+        .exchange_code(&code, &login.pkce_verifier, &login.nonce)
+        .await
+        .map_err(|e| {
+            AppError::unauthorized()
+                .with_message("Could not verify identity")
+                .with_debug(e.to_string())
+                .at_site(&site)
+        })?;
+
+    let linked = sqlx::query_as::<_, (Uuid, String)>(
+        "SELECT uuid, name FROM authors WHERE oidc_subject = $1",
+    )
+    .bind(&claims.sub)
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (author_uuid, author_name) = match linked {
+        Some(author) => author,
+        None => {
+            // First login, link the author whose signing email matches
+            let email = claims
+                .email
+                .filter(|_| claims.email_verified)
+                .ok_or_else(|| {
+                    AppError::forbidden()
+                        .with_message("No author is linked to this identity")
+                        .at_site(&site)
+                })?;
+
+            sqlx::query_as::<_, (Uuid, String)>(
+                r#"
+                UPDATE authors SET oidc_subject = $1
+                WHERE id = (
+                    SELECT id FROM authors
+                    WHERE signing_email = $2 AND oidc_subject IS NULL
+                    ORDER BY id LIMIT 1
+                )
+                RETURNING uuid, name
+                "#,
+            )
+            .bind(&claims.sub)
+            .bind(&email)
+            .fetch_optional(&pool)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?
+            .ok_or_else(|| {
+                AppError::forbidden()
+                    .with_message("No author is linked to this identity")
+                    .at_site(&site)
+            })?
+        }
+    };
+
+    // Sessions carry no limit of their own, the author's site roles decide what they can do
+    let token = generate_token();
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (token_id, expires_at) = sqlx::query_as::<_, (i32, DateTime<Utc>)>(
+        r#"
+        INSERT INTO api_tokens (name, token_hash, capability, author_uuid, expires_at)
+        VALUES ($1, $2, $3, $4, NOW() + make_interval(hours => $5))
+        RETURNING id, expires_at
+        "#,
+    )
+    .bind(format!("Session for {}", author_name))
+    .bind(hash_token(&token))
+    .bind(Capability::Admin)
+    .bind(author_uuid)
+    .bind(SESSION_TTL_HOURS as i32)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "INSERT INTO api_token_sites (token_id, site_id) VALUES ($1, $2)",
+        token_id,
+        login.site_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        SESSION_COOKIE,
+        token,
+        SESSION_TTL_HOURS * 3600
+    );
+    if !site.is_local() {
+        cookie.push_str("; Secure");
+    }
+
+    Ok((
+        [(SET_COOKIE, cookie)],
+        Json(SessionResponse {
+            token,
+            author_uuid,
+            expires_at,
+        }),
+    ))
+}
+
+pub async fn logout(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let raw = raw_credential(&headers)
+        .map_err(|e| e.at_site(&site))?
+        .ok_or_else(|| AppError::unauthorized().at_site(&site))?;
+
+    // Only sessions can be ended this way, API tokens are revoked through /api/tokens
+    sqlx::query!(
+        "UPDATE api_tokens SET revoked_at = NOW() WHERE token_hash = $1 AND author_uuid IS NOT NULL AND expires_at IS NOT NULL",
+        hash_token(raw)
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(
+            SET_COOKIE,
+            format!(
+                "{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+                SESSION_COOKIE
+            ),
+        )],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::tests::{Codes, authorize_as, start_issuer};
+    use crate::testing;
+    use axum::{
+        body::to_bytes,
+        http::{Response, header::LOCATION},
+    };
+    use serde_json::Value;
+
+    // A site and author of their own for each test, so tests can share a database
+    struct Fixture {
+        pool: PgPool,
+        config: AppConfig,
+        codes: Codes,
+        site: SiteIdentity,
+        author_uuid: Uuid,
+        email: String,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let pool = testing::pool().await;
+            let (oidc, codes) = start_issuer().await;
+            let config = AppConfig {
+                oidc: Some(oidc),
+                ..testing::config()
+            };
+            let site = testing::create_site(&pool).await;
+
+            let email = format!("{}@example.com", testing::unique("editor"));
+            let author_uuid = sqlx::query_scalar!(
+                "INSERT INTO authors (name, signing_email) VALUES ('Editor', $1) RETURNING uuid",
+                email
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+            Fixture {
+                pool,
+                config,
+                codes,
+                site,
+                author_uuid,
+                email,
+            }
+        }
+
+        // Start a login, returning its state and the issuer's code for `subject`
+        async fn login_as(&self, subject: &str, email: &str) -> (String, String) {
+            let response = oidc_login(
+                State(self.pool.clone()),
+                State(self.config.clone()),
+                self.site.clone(),
+            )
+            .await
+            .into_response();
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+            let url = response.headers()[LOCATION].to_str().unwrap();
+            let state = reqwest::Url::parse(url)
+                .unwrap()
+                .query_pairs()
+                .find(|(key, _)| key == "state")
+                .map(|(_, value)| value.into_owned())
+                .unwrap();
+            (state, authorize_as(&self.codes, url, subject, email))
+        }
+
+        async fn callback(&self, state: &str, code: &str) -> Response<axum::body::Body> {
+            let params = CallbackParams {
+                state: state.to_string(),
+                code: Some(code.to_string()),
+                error: None,
+            };
+            oidc_callback(
+                State(self.pool.clone()),
+                State(self.config.clone()),
+                self.site.clone(),
+                Ok(Query(params)),
+            )
+            .await
+            .into_response()
+        }
+
+        async fn cleanup(self) {
+            sqlx::query!(
+                "DELETE FROM api_tokens WHERE author_uuid = $1",
+                self.author_uuid
+            )
+            .execute(&self.pool)
+            .await
+            .unwrap();
+            sqlx::query!("DELETE FROM authors WHERE uuid = $1", self.author_uuid)
+                .execute(&self.pool)
+                .await
+                .unwrap();
+            sqlx::query!("DELETE FROM oidc_logins WHERE site_id = $1", self.site.id)
+                .execute(&self.pool)
+                .await
+                .unwrap();
+            testing::delete_site(&self.pool, &self.site).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn callback_links_author_and_starts_session() {
+        let fixture = Fixture::new().await;
+        let subject = Uuid::new_v4().to_string();
+
+        let (state, code) = fixture.login_as(&subject, &fixture.email).await;
+        let response = fixture.callback(&state, &code).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap().to_string();
+        let body: Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        let token = body["token"].as_str().unwrap();
+        assert_eq!(body["author_uuid"], fixture.author_uuid.to_string());
+        assert!(cookie.starts_with(&format!("{}={};", SESSION_COOKIE, token)));
+        assert!(cookie.contains("HttpOnly") && cookie.contains("Secure"));
+
+        let linked = sqlx::query_scalar!(
+            "SELECT oidc_subject FROM authors WHERE uuid = $1",
+            fixture.author_uuid
+        )
+        .fetch_one(&fixture.pool)
+        .await
+        .unwrap();
+        assert_eq!(linked.as_deref(), Some(subject.as_str()));
+
+        let session = sqlx::query!(
+            r#"
+            SELECT author_uuid, ARRAY(
+                SELECT site_id FROM api_token_sites WHERE token_id = api_tokens.id
+            ) AS "sites!"
+            FROM api_tokens WHERE token_hash = $1
+            "#,
+            hash_token(token)
+        )
+        .fetch_one(&fixture.pool)
+        .await
+        .unwrap();
+        assert_eq!(session.author_uuid, Some(fixture.author_uuid));
+        assert_eq!(session.sites, vec![fixture.site.id]);
+
+        // Logins are single use
+        let replayed = fixture.callback(&state, &code).await;
+        assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+
+        // Once linked, the subject logs in as the author whatever its email
+        let (state, code) = fixture.login_as(&subject, "changed@example.com").await;
+        assert_eq!(
+            fixture.callback(&state, &code).await.status(),
+            StatusCode::OK
+        );
+
+        fixture.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn callback_checks_state_and_nonce() {
+        let fixture = Fixture::new().await;
+        let subject = Uuid::new_v4().to_string();
+
+        let (_, code) = fixture.login_as(&subject, &fixture.email).await;
+        let response = fixture.callback("not-a-login", &code).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // An ID token for another login's nonce is refused
+        let (state, code) = fixture.login_as(&subject, &fixture.email).await;
+        sqlx::query!(
+            "UPDATE oidc_logins SET nonce = 'other-nonce' WHERE state = $1",
+            state
+        )
+        .execute(&fixture.pool)
+        .await
+        .unwrap();
+        let response = fixture.callback(&state, &code).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // A login started here can't be finished on another site
+        let other = testing::create_site(&fixture.pool).await;
+        let (state, code) = fixture.login_as(&subject, &fixture.email).await;
+        let params = CallbackParams {
+            state,
+            code: Some(code),
+            error: None,
+        };
+        let response = oidc_callback(
+            State(fixture.pool.clone()),
+            State(fixture.config.clone()),
+            other.clone(),
+            Ok(Query(params)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        testing::delete_site(&fixture.pool, &other).await;
+
+        let linked = sqlx::query_scalar!(
+            "SELECT oidc_subject FROM authors WHERE uuid = $1",
+            fixture.author_uuid
+        )
+        .fetch_one(&fixture.pool)
+        .await
+        .unwrap();
+        assert_eq!(linked, None);
+
+        fixture.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn callback_rejects_unknown_subjects() {
+        let fixture = Fixture::new().await;
+        let subject = Uuid::new_v4().to_string();
+
+        let (state, code) = fixture.login_as(&subject, "nobody@example.com").await;
+        let response = fixture.callback(&state, &code).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let sessions = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM api_tokens WHERE author_uuid = $1"#,
+            fixture.author_uuid
+        )
+        .fetch_one(&fixture.pool)
+        .await
+        .unwrap();
+        assert_eq!(sessions, 0);
+
+        fixture.cleanup().await;
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    error::AppError,
+    extractors::SiteIdentity,
+    routes::feeds::escape_xml,
+    urls::{SiteUrls, site_url},
+};
+use axum::{
+    extract::{Path, State},
+    http::header::CONTENT_TYPE,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+// The most URLs the sitemap protocol allows in one file
+#[cfg(not(test))]
+const SITEMAP_SIZE: i64 = 50_000;
+// Small enough for tests to reach a second page
+#[cfg(test)]
+const SITEMAP_SIZE: i64 = 2;
+
+const XML: &str = "application/xml; charset=utf-8";
+
+// Everything the site shows publicly. Tags and authors are only listed when the site
+// has a URL pattern for them ($2 and $3), and only while they have a visible post.
+const SITEMAP_URLS: &str = r#"
+    WITH visible AS (
+        SELECT id, uuid, slug, author_uuid, updated_at FROM posts
+        WHERE id IN (SELECT post_id FROM post_sites WHERE site_id = $1)
+        AND status = 'published' AND published_at <= NOW()
+    )
+    SELECT 'post' AS kind, uuid, COALESCE(slug, uuid::TEXT) AS key, updated_at AS lastmod
+    FROM visible
+
+    UNION ALL
+
+    SELECT 'tag', t.tag_uuid, t.tag_name, MAX(v.updated_at)
+    FROM tag_stats t
+    JOIN post_tags pt ON pt.tag_uuid = t.tag_uuid
+    JOIN visible v ON v.id = pt.post_id
+    WHERE $2
+    GROUP BY t.tag_uuid, t.tag_name
+
+    UNION ALL
+
+    SELECT 'author', a.uuid, a.name, MAX(v.updated_at)
+    FROM authors a
+    JOIN visible v ON v.author_uuid = a.uuid
+    WHERE $3
+    GROUP BY a.uuid, a.name
+"#;
+
+#[derive(sqlx::FromRow)]
+struct SitemapUrl {
+    kind: String,
+    uuid: Uuid,
+    key: String,
+    lastmod: DateTime<Utc>,
+}
+
+/// The site's sitemap, or an index of numbered sitemaps once there are too many URLs for one
+pub async fn sitemap(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+) -> Result<impl IntoResponse, AppError> {
+    let urls = SiteUrls::load(&pool, &site)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let total = count_urls(&pool, &site, &urls).await?;
+
+    if total <= SITEMAP_SIZE {
+        let body = urlset(&pool, &site, &urls, 0).await?;
+        return Ok(([(CONTENT_TYPE, XML)], body));
+    }
+
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n"
+    ));
+    let pages = (total + SITEMAP_SIZE - 1) / SITEMAP_SIZE;
+    for page in 1..=pages {
+        let _ = writeln!(
+            xml,
+            "<sitemap><loc>{}/sitemaps/{}.xml</loc></sitemap>",
+            escape_xml(urls.base()),
+            page
+        );
+    }
+    xml.push_str("</sitemapindex>\n");
+
+    Ok(([(CONTENT_TYPE, XML)], xml))
+}
+
+/// One of the numbered sitemaps listed in the index, counting from 1
+pub async fn sitemap_page(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    Path(file): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let page = file
+        .strip_suffix(".xml")
+        .and_then(|n| n.parse::<i64>().ok())
+        .filter(|n| *n >= 1)
+        .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    let urls = SiteUrls::load(&pool, &site)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Past the last page there's nothing to list
+    let offset = (page - 1).saturating_mul(SITEMAP_SIZE);
+    if offset >= count_urls(&pool, &site, &urls).await? {
+        return Err(AppError::not_found().at_site(&site));
+    }
+    let body = urlset(&pool, &site, &urls, offset).await?;
+
+    Ok(([(CONTENT_TYPE, XML)], body))
+}
+
+async fn count_urls(pool: &PgPool, site: &SiteIdentity, urls: &SiteUrls) -> Result<i64, AppError> {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM ({}) AS urls", SITEMAP_URLS))
+        .bind(site.id)
+        .bind(urls.has_tags())
+        .bind(urls.has_authors())
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))
+}
+
+async fn urlset(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    urls: &SiteUrls,
+    offset: i64,
+) -> Result<String, AppError> {
+    let query = format!(
+        "SELECT kind, uuid, key, lastmod FROM ({}) AS urls ORDER BY kind, uuid LIMIT $4 OFFSET $5",
+        SITEMAP_URLS
+    );
+    let rows = sqlx::query_as::<_, SitemapUrl>(&query)
+        .bind(site.id)
+        .bind(urls.has_tags())
+        .bind(urls.has_authors())
+        .bind(SITEMAP_SIZE)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n"
+    ));
+    for row in rows {
+        let loc = match row.kind.as_str() {
+            "post" => Some(urls.post(Some(&row.key), row.uuid)),
+            "tag" => urls.tag(&row.key, row.uuid),
+            _ => urls.author(&row.key, row.uuid),
+        };
+        let Some(loc) = loc else {
+            continue;
+        };
+        let _ = writeln!(
+            xml,
+            "<url><loc>{}</loc><lastmod>{}</lastmod></url>",
+            escape_xml(&loc),
+            row.lastmod.format("%Y-%m-%dT%H:%M:%SZ")
+        );
+    }
+    xml.push_str("</urlset>\n");
+
+    Ok(xml)
+}
+
+/// The site's robots.txt, by default letting everything in and pointing at the sitemap
+pub async fn robots_txt(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+) -> Result<impl IntoResponse, AppError> {
+    let configured = sqlx::query_scalar!("SELECT robots_txt FROM sites WHERE id = $1", site.id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .flatten();
+
+    let body = configured.unwrap_or_else(|| {
+        format!(
+            "User-agent: *\nAllow: /\n\nSitemap: {}/sitemap.xml\n",
+            site_url(&site)
+        )
+    });
+
+    Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{body::to_bytes, http::StatusCode, response::Response};
+
+    async fn body(response: Response) -> String {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    async fn page(pool: &PgPool, site: &SiteIdentity, file: &str) -> Result<String, StatusCode> {
+        match sitemap_page(State(pool.clone()), site.clone(), Path(file.to_string())).await {
+            Ok(response) => Ok(body(response.into_response()).await),
+            Err(e) => Err(e.status),
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_stop_at_the_last_url() {
+        let pool = testing::pool().await;
+        let site = testing::create_site(&pool).await;
+        for _ in 0..3 {
+            testing::create_post(&pool, &site, "published", &[]).await;
+        }
+        testing::create_post(&pool, &site, "draft", &[]).await;
+
+        let Ok(index) = sitemap(State(pool.clone()), site.clone()).await else {
+            panic!("the sitemap failed");
+        };
+        let index = body(index.into_response()).await;
+        assert!(index.contains("<sitemapindex"), "{}", index);
+        assert_eq!(index.matches("<sitemap>").count(), 2, "{}", index);
+
+        let first = page(&pool, &site, "1.xml").await.unwrap();
+        assert_eq!(first.matches("<url>").count(), 2, "{}", first);
+        let second = page(&pool, &site, "2.xml").await.unwrap();
+        assert_eq!(second.matches("<url>").count(), 1, "{}", second);
+
+        for file in ["3.xml", "0.xml", "two.xml", "1.txt"] {
+            assert_eq!(page(&pool, &site, file).await, Err(StatusCode::NOT_FOUND));
+        }
+
+        testing::delete_site(&pool, &site).await;
+    }
+}
@@ -1,40 +1,65 @@
-use crate::{error::AppError, extractors::SiteIdentity};
+use crate::{
+    auth::Role,
+    error::AppError,
+    extractors::{Actor, SiteIdentity},
+    render::{is_theme, theme_css},
+};
 use axum::{
     Json,
     extract::{Path, State},
-    http::StatusCode,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
 };
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use uuid::Uuid;
 
 #[derive(Serialize)]
 pub struct SiteResponse {
     pub id: i32,
     pub domain: String,
-    pub site_mask_bit: i32,
+    pub site_mask_bit: Option<i32>,
     pub requires_auth: bool,
+    pub highlight_theme: String,
+    pub highlight_class_prefix: String,
+    pub post_url_pattern: String,
+    pub tag_url_pattern: Option<String>,
+    pub author_url_pattern: Option<String>,
+    /// Without one the site serves a robots.txt allowing everything
+    pub robots_txt: Option<String>,
 }
 
 pub async fn get_sites(
     State(pool): State<PgPool>,
-    site: SiteIdentity,
+    actor: Actor,
 ) -> Result<Json<Vec<SiteResponse>>, AppError> {
-    // Only allow localhost to manage sites
-    if !site.domain.starts_with("localhost") && !site.domain.starts_with("127.0.0.1") {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+    // Only allow owners to manage sites
+    actor.require(Role::Owner)?;
 
     let sites = sqlx::query!(
-        "SELECT id, domain, site_mask_bit, requires_auth FROM sites"
+        r#"
+        SELECT
+            id, domain, site_mask_bit, requires_auth, highlight_theme, highlight_class_prefix,
+            post_url_pattern, tag_url_pattern, author_url_pattern, robots_txt
+        FROM sites
+        "#
     )
     .fetch_all(&pool)
     .await?
     .into_iter()
+    // Owning one site doesn't make the others any of the owner's business
+    .filter(|row| actor.role_at(row.id) >= Some(Role::Viewer))
     .map(|row| SiteResponse {
         id: row.id,
         domain: row.domain,
         site_mask_bit: row.site_mask_bit,
         requires_auth: row.requires_auth.unwrap_or(false),
+        highlight_theme: row.highlight_theme,
+        highlight_class_prefix: row.highlight_class_prefix,
+        post_url_pattern: row.post_url_pattern,
+        tag_url_pattern: row.tag_url_pattern,
+        author_url_pattern: row.author_url_pattern,
+        robots_txt: row.robots_txt,
     })
     .collect();
 
@@ -49,62 +74,316 @@ pub struct CreateSiteRequest {
 
 pub async fn create_site(
     State(pool): State<PgPool>,
-    site: SiteIdentity,
+    actor: Actor,
     Json(payload): Json<CreateSiteRequest>,
 ) -> Result<Json<SiteResponse>, AppError> {
-    if !site.domain.starts_with("localhost") && !site.domain.starts_with("127.0.0.1") {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+    actor.require(Role::Owner)?;
 
-    let existing_sites = sqlx::query!("SELECT site_mask_bit FROM sites")
-        .fetch_all(&pool)
+    let mut tx = pool.begin().await?;
+
+    // Two sites created at once would pick the same free bit, so they take turns.
+    // Reads carry on as normal.
+    sqlx::query!("LOCK TABLE sites IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
         .await?;
 
-    let mut next_bit = existing_sites
-        .iter()
-        .map(|s| s.site_mask_bit)
-        .max()
-        .unwrap_or(0);
+    // Legacy bitmasks only reach 31 sites, newer sites get a bit while any are free
+    let taken: Vec<i32> = sqlx::query_scalar!(
+        r#"SELECT site_mask_bit AS "bit!" FROM sites WHERE site_mask_bit IS NOT NULL"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
 
-    if next_bit == 0 {
-        next_bit = 1;
-    } else {
-        next_bit <<= 1;
-    }
+    let next_bit = (0..31).map(|i| 1 << i).find(|bit| !taken.contains(bit));
 
     let row = sqlx::query!(
         r#"
         INSERT INTO sites (domain, site_mask_bit, requires_auth)
         VALUES ($1, $2, $3)
-        RETURNING id, domain, site_mask_bit, requires_auth
+        RETURNING
+            id, domain, site_mask_bit, requires_auth, highlight_theme, highlight_class_prefix,
+            post_url_pattern, tag_url_pattern, author_url_pattern, robots_txt
         "#,
         payload.domain,
         next_bit,
         payload.requires_auth
     )
-    .fetch_one(&pool)
+    .fetch_one(&mut *tx)
     .await?;
 
+    // Whoever creates a site owns it
+    if let Some(author_uuid) = actor.author_uuid {
+        sqlx::query!(
+            "INSERT INTO site_roles (author_uuid, site_id, role) VALUES ($1, $2, 'owner')",
+            author_uuid,
+            row.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(SiteResponse {
+        id: row.id,
+        domain: row.domain,
+        site_mask_bit: row.site_mask_bit,
+        requires_auth: row.requires_auth.unwrap_or(false),
+        highlight_theme: row.highlight_theme,
+        highlight_class_prefix: row.highlight_class_prefix,
+        post_url_pattern: row.post_url_pattern,
+        tag_url_pattern: row.tag_url_pattern,
+        author_url_pattern: row.author_url_pattern,
+        robots_txt: row.robots_txt,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateSiteRequest {
+    pub highlight_theme: Option<String>,
+    pub highlight_class_prefix: Option<String>,
+    /// Where the frontend shows a post, e.g. `/blog/{slug}`. Can use `{slug}` and `{uuid}`.
+    pub post_url_pattern: Option<String>,
+    /// Can use `{name}` and `{uuid}`, empty to leave tags out of the sitemap
+    pub tag_url_pattern: Option<String>,
+    /// Can use `{name}` and `{uuid}`, empty to leave authors out of the sitemap
+    pub author_url_pattern: Option<String>,
+    /// Empty to go back to the default
+    pub robots_txt: Option<String>,
+}
+
+/// Change a site's settings, leaving out a field keeps its current value.
+/// Optional settings are cleared by sending an empty string.
+pub async fn update_site(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(id): Path<i32>,
+    Json(payload): Json<UpdateSiteRequest>,
+) -> Result<Json<SiteResponse>, AppError> {
+    require_site_owner(&pool, &site, &actor, id).await?;
+
+    if let Some(theme) = &payload.highlight_theme
+        && !is_theme(theme)
+    {
+        return Err(AppError::bad_request()
+            .with_message(format!("Unknown highlight theme {}", theme))
+            .at_site(&site));
+    }
+
+    if let Some(prefix) = &payload.highlight_class_prefix
+        && !prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(AppError::bad_request()
+            .with_message("Class prefixes may only use letters, digits, - and _")
+            .at_site(&site));
+    }
+
+    // Empty clears the tag and author patterns, posts always need one
+    let patterns = [
+        ("post_url_pattern", payload.post_url_pattern.as_deref()),
+        (
+            "tag_url_pattern",
+            payload.tag_url_pattern.as_deref().filter(|p| !p.is_empty()),
+        ),
+        (
+            "author_url_pattern",
+            payload
+                .author_url_pattern
+                .as_deref()
+                .filter(|p| !p.is_empty()),
+        ),
+    ];
+    for (field, pattern) in patterns {
+        if let Some(pattern) = pattern
+            && !is_url_pattern(pattern)
+        {
+            return Err(AppError::bad_request()
+                .with_message(format!(
+                    "{} must be a path starting with / or an http(s) URL",
+                    field
+                ))
+                .at_site(&site));
+        }
+    }
+
+    let row = sqlx::query!(
+        r#"
+        UPDATE sites
+        SET
+            highlight_theme = COALESCE($2, highlight_theme),
+            highlight_class_prefix = COALESCE($3, highlight_class_prefix),
+            post_url_pattern = COALESCE($4, post_url_pattern),
+            tag_url_pattern = CASE WHEN $5::TEXT IS NULL THEN tag_url_pattern ELSE NULLIF($5, '') END,
+            author_url_pattern = CASE WHEN $6::TEXT IS NULL THEN author_url_pattern ELSE NULLIF($6, '') END,
+            robots_txt = CASE WHEN $7::TEXT IS NULL THEN robots_txt ELSE NULLIF($7, '') END
+        WHERE id = $1
+        RETURNING
+            id, domain, site_mask_bit, requires_auth, highlight_theme, highlight_class_prefix,
+            post_url_pattern, tag_url_pattern, author_url_pattern, robots_txt
+        "#,
+        id,
+        payload.highlight_theme,
+        payload.highlight_class_prefix,
+        payload.post_url_pattern,
+        payload.tag_url_pattern,
+        payload.author_url_pattern,
+        payload.robots_txt
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
     Ok(Json(SiteResponse {
         id: row.id,
         domain: row.domain,
         site_mask_bit: row.site_mask_bit,
         requires_auth: row.requires_auth.unwrap_or(false),
+        highlight_theme: row.highlight_theme,
+        highlight_class_prefix: row.highlight_class_prefix,
+        post_url_pattern: row.post_url_pattern,
+        tag_url_pattern: row.tag_url_pattern,
+        author_url_pattern: row.author_url_pattern,
+        robots_txt: row.robots_txt,
     }))
 }
 
+fn is_url_pattern(pattern: &str) -> bool {
+    pattern.starts_with('/') || pattern.starts_with("https://") || pattern.starts_with("http://")
+}
+
+/// The stylesheet for highlighted code on the current site
+pub async fn highlight_css(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+) -> Result<impl IntoResponse, AppError> {
+    let theme = sqlx::query_scalar!("SELECT highlight_theme FROM sites WHERE id = $1", site.id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .unwrap_or_else(|| "InspiredGitHub".to_string());
+
+    let css = theme_css(&theme, &site.highlight_class_prefix).ok_or_else(|| {
+        AppError::not_found()
+            .with_message("Unknown highlight theme")
+            .at_site(&site)
+    })?;
+
+    Ok(([(CONTENT_TYPE, "text/css; charset=utf-8")], css))
+}
+
 pub async fn delete_site(
     State(pool): State<PgPool>,
     site: SiteIdentity,
+    actor: Actor,
     Path(id): Path<i32>,
 ) -> Result<StatusCode, AppError> {
-    if !site.domain.starts_with("localhost") && !site.domain.starts_with("127.0.0.1") {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+    actor.require(Role::Owner)?;
+    require_site_owner(&pool, &site, &actor, id).await?;
 
+    // Roles and memberships go with the site
     sqlx::query!("DELETE FROM sites WHERE id = $1", id)
         .execute(&pool)
         .await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+// Make sure the site exists and the actor owns it
+async fn require_site_owner(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    actor: &Actor,
+    id: i32,
+) -> Result<(), AppError> {
+    sqlx::query_scalar!("SELECT id FROM sites WHERE id = $1", id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?
+        .ok_or_else(|| AppError::not_found().at_site(site))?;
+
+    actor.require_on(&[id], Role::Owner)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct RoleResponse {
+    pub author_uuid: Uuid,
+    pub role: Role,
+}
+
+pub async fn get_roles(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<RoleResponse>>, AppError> {
+    require_site_owner(&pool, &site, &actor, id).await?;
+
+    let roles = sqlx::query_as::<_, RoleResponse>(
+        "SELECT author_uuid, role FROM site_roles WHERE site_id = $1",
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(roles))
+}
+
+#[derive(Deserialize)]
+pub struct GrantRoleRequest {
+    pub role: Role,
+}
+
+pub async fn grant_role(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path((id, author_uuid)): Path<(i32, Uuid)>,
+    Json(payload): Json<GrantRoleRequest>,
+) -> Result<StatusCode, AppError> {
+    require_site_owner(&pool, &site, &actor, id).await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO site_roles (author_uuid, site_id, role)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (author_uuid, site_id)
+        DO UPDATE SET role = EXCLUDED.role
+        "#,
+    )
+    .bind(author_uuid)
+    .bind(id)
+    .bind(payload.role)
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn revoke_role(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path((id, author_uuid)): Path<(i32, Uuid)>,
+) -> Result<StatusCode, AppError> {
+    require_site_owner(&pool, &site, &actor, id).await?;
+
+    let result = sqlx::query!(
+        "DELETE FROM site_roles WHERE author_uuid = $1 AND site_id = $2",
+        author_uuid,
+        id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found().at_site(&site));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,117 +1,555 @@
-use crate::{error::AppError, extractors::SiteIdentity, models::Tag};
-use crate::params::SearchParams;
+use crate::{
+    auth::Role,
+    config::{AppConfig, PaginationConfig, PopularityConfig},
+    error::{AppError, FieldError},
+    extractors::{Actor, Client, SiteIdentity},
+    models::Tag,
+    pagination::{Keyset, Listing, Page, PageRequest},
+    popularity,
+    render::render_markdown,
+    routes::posts::{PostParams, PostResponse, tag_posts},
+    webhooks,
+};
+use crate::params::{SearchParams, SortDirection};
 use axum::{
     Json,
-    extract::{Path, Query, State, rejection::QueryRejection},
+    extract::{OriginalUri, Path, Query, State, rejection::QueryRejection},
     http::StatusCode,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
 
 #[derive(Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum TagSort {
     Usage,
+    /// Selections within `window`
     Popularity,
+    /// Recent selections first, older ones counting for less and less. Scores fall as
+    /// time passes, so cursors can skip or repeat tags that move in between pages.
+    Trending,
     Alphabetical,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Debug)]
+pub struct TagParams {
+    #[serde(flatten)]
+    base: SearchParams<TagSort>,
+    /// One of the configured popularity windows, e.g. 24h
+    window: Option<String>,
+}
+
+// The column a tag listing is ordered on and its type. `site` is the SQL for the site
+// whose selections count, NULL for every site.
+fn tag_order(
+    sort: Option<TagSort>,
+    window_hours: i32,
+    popularity: &PopularityConfig,
+    site: &str,
+) -> (String, &'static str) {
+    match sort {
+        Some(TagSort::Popularity) => (
+            format!("tag_popularity(tag_uuid, {}, {})", site, window_hours),
+            "BIGINT",
+        ),
+        Some(TagSort::Trending) => (
+            format!(
+                "tag_trending(tag_uuid, {}, {})",
+                site, popularity.trending_half_life_hours
+            ),
+            "DOUBLE PRECISION",
+        ),
+        Some(TagSort::Usage) => ("use_count".to_string(), "INTEGER"),
+        _ => ("tag_name".to_string(), "TEXT"),
+    }
+}
+
+// Trending reads hottest first unless asked otherwise
+fn tag_direction(params: &SearchParams<TagSort>) -> SortDirection {
+    match (params.sort(), params.sortable.sort_by) {
+        (Some(TagSort::Trending), None) => SortDirection::Desc,
+        _ => params.sort_by(),
+    }
+}
+
+// The window's length in hours, the configured default if the request has none
+fn tag_window(
+    window: Option<&str>,
+    popularity: &PopularityConfig,
+    site: &SiteIdentity,
+) -> Result<i32, AppError> {
+    let window = window.unwrap_or(&popularity.default_window);
+    popularity.window_hours(window).ok_or_else(|| {
+        AppError::bad_request()
+            .with_message("Invalid query parameters")
+            .with_fields(vec![FieldError::new(
+                "window",
+                format!("Must be one of {}", popularity.windows.join(", ")),
+            )])
+            .at_site(site)
+    })
+}
+
+// Selections of the tag in each configured window, keyed by window
+fn popularity_column(popularity: &PopularityConfig, site: &str) -> String {
+    let windows: Vec<String> = popularity
+        .windows
+        .iter()
+        .filter_map(|w| {
+            popularity
+                .window_hours(w)
+                .map(|hours| format!("'{}', tag_popularity(tag_uuid, {}, {})", w, site, hours))
+        })
+        .collect();
+    format!("jsonb_build_object({}) AS popularity", windows.join(", "))
+}
+
+fn tag_page(
+    params: &SearchParams<TagSort>,
+    keyset: &Keyset,
+    limits: &PaginationConfig,
+    endpoint: &str,
+    site: &SiteIdentity,
+) -> Result<(PageRequest, String), AppError> {
+    let ordering = format!("tags:{}:{}", keyset.column, keyset.direction.to_sql());
+    let page = params
+        .bounds(limits.default_limit, limits.max_limit(endpoint))
+        .and_then(|bounds| PageRequest::new(bounds, params.cursor(), &ordering, keyset.key_type))
+        .map_err(|e| e.at_site(site))?;
+    Ok((page, ordering))
+}
+
+// A Tag as admins see it, selected from tag_stats
+const ADMIN_TAG_COLUMNS: &str = "
+    tag_name, tag_uuid, use_count, selected_count, parent_uuid,
+    COALESCE((
+        SELECT BIT_OR(s.site_mask_bit) FROM tag_sites ts
+        JOIN sites s ON s.id = ts.site_id
+        WHERE ts.tag_uuid = tag_stats.tag_uuid
+    ), 0) AS visibility_mask,
+    ARRAY(SELECT site_id FROM tag_sites WHERE tag_uuid = tag_stats.tag_uuid ORDER BY site_id) AS sites,
+    COALESCE((
+        SELECT jsonb_object_agg(site_id, use_count) FROM tag_sites
+        WHERE tag_uuid = tag_stats.tag_uuid
+    ), '{}') AS site_counts,
+    ARRAY(SELECT alias FROM tag_aliases WHERE tag_uuid = tag_stats.tag_uuid ORDER BY alias) AS aliases";
+
+// Tags a listing picks from, shared with its count. The admin listing binds the search
+// pattern as $1, the public one the site as $1 and the search pattern as $2. Public
+// listings only see the tags on the site's published posts, counted on that site alone.
+const ADMIN_TAG_LISTING: &str = "FROM tag_stats WHERE ($1::TEXT IS NULL OR tag_name ILIKE $1)";
+const TAG_LISTING: &str = "FROM (
+             SELECT
+                 t.tag_name, t.tag_uuid, t.parent_uuid, t.selected_count, ts.use_count,
+                 d.description, d.description_html, d.description_class_prefix,
+                 d.colour, d.icon, d.cover_image
+             FROM tag_stats t
+             JOIN published_tag_sites ts ON ts.tag_uuid = t.tag_uuid
+             LEFT JOIN tag_details d ON d.tag_uuid = t.tag_uuid AND d.site_id = ts.site_id
+             WHERE ts.site_id = $1
+         ) AS site_tags
+         WHERE ($2::TEXT IS NULL OR tag_name ILIKE $2)";
+
+// The TagResponse columns of TAG_LISTING
+const TAG_COLUMNS: &str = "tag_name, tag_uuid, use_count, parent_uuid, description,
+    description_html, description_class_prefix, colour, icon, cover_image";
+
+#[derive(Serialize, sqlx::FromRow)]
 pub struct TagResponse {
+    #[sqlx(rename = "tag_name")]
     pub name: String,
+    #[sqlx(rename = "tag_uuid")]
     pub uuid: uuid::Uuid,
+    /// How many published posts on this site have the tag
+    pub use_count: i32,
+    /// The broader tag this one sits under
+    pub parent_uuid: Option<uuid::Uuid>,
+    /// Markdown, as set for this site
+    pub description: Option<String>,
+    /// `description` rendered and sanitized
+    pub description_html: Option<String>,
+    // The highlighting class prefix description_html was rendered with
+    #[serde(skip)]
+    pub description_class_prefix: Option<String>,
+    pub colour: Option<String>,
+    pub icon: Option<String>,
+    pub cover_image: Option<String>,
+    /// Selections on this site in each popularity window, e.g. {"24h": 3, "7d": 12}
+    pub popularity: serde_json::Value,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub sort_key: Option<String>,
+}
+
+impl TagResponse {
+    // Descriptions are stored rendered, only ones from before that or from before the
+    // site changed its class prefix are rendered here
+    fn rendered(mut self, site: &SiteIdentity) -> Self {
+        if self.description_class_prefix.as_deref() != Some(&site.highlight_class_prefix) {
+            self.description_html = self
+                .description
+                .as_deref()
+                .map(|d| render_markdown(d, &site.highlight_class_prefix));
+        }
+        self
+    }
 }
 
 pub async fn admin_fetch_tags(
     State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
     site: SiteIdentity,
-    params: Result<Query<SearchParams<TagSort>>, QueryRejection>,
-) -> Result<Json<Vec<Tag>>, AppError> {
-    if !site.requires_auth {
-        return Err(AppError::unauthorized().at_site(&site));
-    }
+    actor: Actor,
+    OriginalUri(uri): OriginalUri,
+    params: Result<Query<TagParams>, QueryRejection>,
+) -> Result<Listing<Tag>, AppError> {
+    actor.require(Role::Viewer)?;
 
-    let Query(params) = params.map_err(|e| {
+    let Query(TagParams {
+        base: params,
+        window,
+    }) = params.map_err(|e| {
         AppError::bad_request()
             .with_debug(e.to_string())
             .at_site(&site)
     })?;
 
-    let order_col = match params.sort() {
-        Some(TagSort::Popularity) => "selected_count",
-        Some(TagSort::Usage) => "use_count",
-        _ => "tag_name",
+    // Admins see selections on every site
+    let window_hours = tag_window(window.as_deref(), &config.popularity, &site)?;
+    let (column, key_type) = tag_order(
+        params.sort().copied(),
+        window_hours,
+        &config.popularity,
+        "NULL",
+    );
+    let keyset = Keyset {
+        column: &column,
+        key_type,
+        uuid_column: "tag_uuid",
+        direction: tag_direction(&params),
     };
-
-    let direction = params.sort_by().to_sql();
+    let (page, ordering) = tag_page(&params, &keyset, &config.pagination, "admin_tags", &site)?;
     let search_pattern = params.search().map(|s| format!("%{}%", s));
 
     let query = format!(
-        "SELECT tag_name, tag_uuid, use_count, selected_count, visibility_mask FROM tag_stats
-         WHERE ($3::TEXT IS NULL OR tag_name ILIKE $3)
-         ORDER BY {} {}, tag_name ASC
-         LIMIT $1 OFFSET $2",
-        order_col, direction
+        "SELECT {columns}, {popularity}, ({column})::TEXT AS sort_key
+         {from}
+         AND {after_cursor}
+         ORDER BY {order}
+         LIMIT $2 OFFSET $3",
+        columns = ADMIN_TAG_COLUMNS,
+        popularity = popularity_column(&config.popularity, "NULL"),
+        column = keyset.column,
+        from = ADMIN_TAG_LISTING,
+        after_cursor = keyset.condition(page.backwards(), 4, 5),
+        order = keyset.order(page.backwards()),
     );
 
     let tags = sqlx::query_as::<_, Tag>(&query)
-        .bind(params.limit())
-        .bind(params.offset())
-        .bind(search_pattern)
+        .bind(&search_pattern)
+        .bind(page.fetch_limit())
+        .bind(page.offset())
+        .bind(page.cursor().map(|c| &c.key))
+        .bind(page.cursor().map(|c| c.uuid))
         .fetch_all(&pool)
         .await
-        .map_err(|e| AppError::from(e).at_site(&site))?;
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .into_iter()
+        .map(|tag| {
+            let key = tag.sort_key.clone().unwrap_or_default();
+            let uuid = tag.tag_uuid;
+            (tag, key, uuid)
+        })
+        .collect();
 
-    Ok(Json(tags))
+    let total = if page.with_total() {
+        let count = sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) {}", ADMIN_TAG_LISTING))
+            .bind(&search_pattern)
+            .fetch_one(&pool)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Listing::new(&page, &ordering, uri, tags).with_total(total))
 }
 
 pub async fn fetch_tags(
     State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
     site: SiteIdentity,
-    params: Result<Query<SearchParams<TagSort>>, QueryRejection>,
-) -> Result<Json<Vec<TagResponse>>, AppError> {
-    let Query(params) = params.map_err(|e| {
+    OriginalUri(uri): OriginalUri,
+    params: Result<Query<TagParams>, QueryRejection>,
+) -> Result<Listing<TagResponse>, AppError> {
+    let Query(TagParams {
+        base: params,
+        window,
+    }) = params.map_err(|e| {
         AppError::bad_request()
             .with_debug(e.to_string())
             .at_site(&site)
     })?;
 
-    let order_col = match params.sort() {
-        Some(TagSort::Popularity) => "selected_count",
-        Some(TagSort::Usage) => "use_count",
-        _ => "tag_name",
+    let window_hours = tag_window(window.as_deref(), &config.popularity, &site)?;
+    let (column, key_type) = tag_order(
+        params.sort().copied(),
+        window_hours,
+        &config.popularity,
+        "$1",
+    );
+    let keyset = Keyset {
+        column: &column,
+        key_type,
+        uuid_column: "tag_uuid",
+        direction: tag_direction(&params),
     };
-
-    let direction = params.sort_by().to_sql();
+    let (page, ordering) = tag_page(&params, &keyset, &config.pagination, "tags", &site)?;
     let search_pattern = params.search().map(|s| format!("%{}%", s));
 
     let query = format!(
-        "SELECT tag_name, tag_uuid FROM tag_stats
-         WHERE (visibility_mask & $1) > 0
-         AND ($4::TEXT IS NULL OR tag_name ILIKE $4)
-         ORDER BY {} {}, tag_name ASC
-         LIMIT $2 OFFSET $3",
-        order_col, direction
+        "SELECT {columns}, {popularity}, ({column})::TEXT AS sort_key {from}
+         AND {after_cursor}
+         ORDER BY {order}
+         LIMIT $3 OFFSET $4",
+        columns = TAG_COLUMNS,
+        popularity = popularity_column(&config.popularity, "$1"),
+        column = keyset.column,
+        from = TAG_LISTING,
+        after_cursor = keyset.condition(page.backwards(), 5, 6),
+        order = keyset.order(page.backwards()),
     );
 
-    let tags = sqlx::query_as::<_, (String, uuid::Uuid)>(&query)
-        .bind(site.mask)
-        .bind(params.limit())
-        .bind(params.offset())
-        .bind(search_pattern)
+    let tags = sqlx::query_as::<_, TagResponse>(&query)
+        .bind(site.id)
+        .bind(&search_pattern)
+        .bind(page.fetch_limit())
+        .bind(page.offset())
+        .bind(page.cursor().map(|c| &c.key))
+        .bind(page.cursor().map(|c| c.uuid))
         .fetch_all(&pool)
         .await
         .map_err(|e| AppError::from(e).at_site(&site))?
         .into_iter()
-        .map(|(name, uuid)| TagResponse { name, uuid })
+        .map(|tag| {
+            let key = tag.sort_key.clone().unwrap_or_default();
+            let uuid = tag.uuid;
+            (tag.rendered(&site), key, uuid)
+        })
         .collect();
 
-    Ok(Json(tags))
+    let total = if page.with_total() {
+        let count = sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) {}", TAG_LISTING))
+            .bind(site.id)
+            .bind(&search_pattern)
+            .fetch_one(&pool)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Listing::new(&page, &ordering, uri, tags).with_total(total))
 }
 
+// A tag as the site shows it, whether or not any of the site's posts have it yet
+async fn site_tag(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    popularity: &PopularityConfig,
+    uuid: Uuid,
+) -> Result<Option<TagResponse>, AppError> {
+    let query = format!(
+        "SELECT {columns}, {popularity} FROM (
+             SELECT
+                 t.tag_name, t.tag_uuid, COALESCE(ts.use_count, 0) AS use_count, t.parent_uuid,
+                 d.description, d.description_html, d.description_class_prefix,
+                 d.colour, d.icon, d.cover_image
+             FROM tag_stats t
+             LEFT JOIN published_tag_sites ts ON ts.tag_uuid = t.tag_uuid AND ts.site_id = $2
+             LEFT JOIN tag_details d ON d.tag_uuid = t.tag_uuid AND d.site_id = $2
+             WHERE t.tag_uuid = resolve_tag_uuid($1)
+         ) AS site_tag",
+        columns = TAG_COLUMNS,
+        popularity = popularity_column(popularity, "$2"),
+    );
+    let tag = sqlx::query_as::<_, TagResponse>(&query)
+        .bind(uuid)
+        .bind(site.id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))?;
+
+    Ok(tag.map(|tag| tag.rendered(site)))
+}
+
+#[derive(Serialize)]
+pub struct TagPageResponse {
+    #[serde(flatten)]
+    pub tag: TagResponse,
+    /// The first page of the tag's posts, or the page asked for with the post listing's
+    /// query parameters
+    pub posts: Page<PostResponse>,
+}
+
+/// A tag with its posts on this site, everything a tag's landing page needs.
+/// Merged tags' uuids give the tag they went into.
+pub async fn get_tag(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    OriginalUri(uri): OriginalUri,
+    Path(uuid): Path<Uuid>,
+    params: Result<Query<PostParams>, QueryRejection>,
+) -> Result<Json<TagPageResponse>, AppError> {
+    let Query(params) = params.map_err(|e| {
+        AppError::bad_request()
+            .with_debug(e.to_string())
+            .at_site(&site)
+    })?;
+
+    // Tags only have a page where some of the site's published posts have them
+    let tag = site_tag(&pool, &site, &config.popularity, uuid)
+        .await?
+        .filter(|tag| tag.use_count > 0)
+        .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    let posts = tag_posts(&pool, &config, &site, uri, &params, &tag.name).await?;
+
+    Ok(Json(TagPageResponse {
+        tag,
+        posts: posts.into_page(),
+    }))
+}
+
+// Longest icon name accepted, emoji and icon font names are far shorter
+const MAX_ICON_LEN: usize = 64;
+
+#[derive(Deserialize)]
+pub struct TagDetailsRequest {
+    /// Markdown
+    pub description: Option<String>,
+    /// A CSS hex colour, #rgb or #rrggbb
+    pub colour: Option<String>,
+    pub icon: Option<String>,
+    /// An http or https URL, or a path on the site
+    pub cover_image: Option<String>,
+}
+
+fn check_tag_details(details: &TagDetailsRequest) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+
+    if let Some(colour) = &details.colour {
+        let hex = colour.strip_prefix('#').unwrap_or_default();
+        if !matches!(hex.len(), 3 | 6) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            errors.push(FieldError::new(
+                "colour",
+                "Must be a hex colour like #1e90ff",
+            ));
+        }
+    }
+
+    if let Some(icon) = &details.icon
+        && (icon.trim().is_empty() || icon.chars().count() > MAX_ICON_LEN)
+    {
+        errors.push(FieldError::new(
+            "icon",
+            format!("Must be 1 to {} characters", MAX_ICON_LEN),
+        ));
+    }
+
+    if let Some(cover_image) = &details.cover_image {
+        let valid = if cover_image.starts_with('/') {
+            !cover_image.starts_with("//")
+        } else {
+            reqwest::Url::parse(cover_image)
+                .map(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+                .unwrap_or(false)
+        };
+        if !valid {
+            errors.push(FieldError::new(
+                "cover_image",
+                "Must be an http or https URL or a path starting with /",
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Set how this site shows a tag, replacing whatever it had. Other sites keep their own.
+pub async fn set_tag_details(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+    Json(payload): Json<TagDetailsRequest>,
+) -> Result<Json<TagResponse>, AppError> {
+    actor.require(Role::Editor)?;
+
+    let errors = check_tag_details(&payload);
+    if !errors.is_empty() {
+        return Err(AppError::bad_request()
+            .with_message("Invalid tag details")
+            .with_fields(errors)
+            .at_site(&site));
+    }
+
+    let description = payload.description.filter(|d| !d.trim().is_empty());
+    let description_html = description
+        .as_deref()
+        .map(|d| render_markdown(d, &site.highlight_class_prefix));
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO tag_details (
+            tag_uuid, site_id, description, description_html, description_class_prefix,
+            colour, icon, cover_image
+        )
+        SELECT tag_uuid, $2, $3, $4, $5, $6, $7, $8
+        FROM tag_stats WHERE tag_uuid = resolve_tag_uuid($1)
+        ON CONFLICT (tag_uuid, site_id) DO UPDATE
+        SET
+            description = EXCLUDED.description,
+            description_html = EXCLUDED.description_html,
+            description_class_prefix = EXCLUDED.description_class_prefix,
+            colour = EXCLUDED.colour,
+            icon = EXCLUDED.icon,
+            cover_image = EXCLUDED.cover_image,
+            updated_at = NOW()
+        "#,
+        uuid,
+        site.id,
+        description,
+        description_html,
+        site.highlight_class_prefix,
+        payload.colour,
+        payload.icon,
+        payload.cover_image
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found().at_site(&site));
+    }
+
+    let tag = site_tag(&pool, &site, &config.popularity, uuid)
+        .await?
+        .ok_or_else(|| AppError::not_found().at_site(&site))?;
+    Ok(Json(tag))
+}
+
+/// Count a visitor picking a tag. Each client counts once a day per tag, repeats are
+/// accepted but ignored.
 pub async fn increment_tag_selection(
     State(pool): State<PgPool>,
     site: SiteIdentity,
+    client: Client,
     Path(identifier): Path<String>,
 ) -> Result<StatusCode, AppError> {
     let tag_uuid = uuid::Uuid::parse_str(&identifier).map_err(|e| {
@@ -120,22 +558,713 @@ pub async fn increment_tag_selection(
             .at_site(&site)
     })?;
 
-    let result = sqlx::query!(
+    let tag_uuid = sqlx::query_scalar!(
         r#"
-            UPDATE tag_stats
-            SET selected_count = selected_count + 1
-            WHERE tag_uuid = $1
-            AND (visibility_mask & $2) > 0
+        SELECT tag_uuid AS "tag_uuid!" FROM published_tag_sites
+        WHERE tag_uuid = resolve_tag_uuid($1) AND site_id = $2
         "#,
         tag_uuid,
-        site.mask
+        site.id
     )
-    .execute(&pool)
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    popularity::record_selection(&pool, tag_uuid, site.id, &client)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct RecomputeResponse {
+    /// Tags whose overall count was wrong
+    pub tags_corrected: u64,
+    /// Per site counts that were missing or wrong
+    pub site_counts_corrected: u64,
+    /// Tags that were still listed on sites where no post has them
+    pub site_counts_removed: u64,
+}
+
+/// Work out every tag count and where each tag shows from posts, fixing any drift
+pub async fn recompute_tags(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+) -> Result<Json<RecomputeResponse>, AppError> {
+    // Every site's tags are recounted, under a lock that holds up writes on all of them
+    actor.require_everywhere(&pool, Role::Owner).await?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Posts can still be read, but not retagged or moved, until the counts are done
+    sqlx::query!("LOCK TABLE post_tags, post_sites IN SHARE MODE")
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let tags_corrected = sqlx::query!(
+        r#"
+        UPDATE tag_stats t
+        SET use_count = counted.use_count
+        FROM (
+            SELECT t.tag_uuid, COUNT(pt.post_id)::INTEGER AS use_count
+            FROM tag_stats t
+            LEFT JOIN post_tags pt ON pt.tag_uuid = t.tag_uuid
+            GROUP BY t.tag_uuid
+        ) AS counted
+        WHERE t.tag_uuid = counted.tag_uuid
+        AND t.use_count <> counted.use_count
+        "#
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .rows_affected();
+
+    let site_counts_removed = sqlx::query!(
+        r#"
+        DELETE FROM tag_sites ts
+        WHERE NOT EXISTS (
+            SELECT 1 FROM post_tags pt
+            JOIN post_sites ps ON ps.post_id = pt.post_id
+            WHERE pt.tag_uuid = ts.tag_uuid AND ps.site_id = ts.site_id
+        )
+        "#
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .rows_affected();
+
+    let site_counts_corrected = sqlx::query!(
+        r#"
+        INSERT INTO tag_sites (tag_uuid, site_id, use_count)
+        SELECT pt.tag_uuid, ps.site_id, COUNT(*)
+        FROM post_tags pt
+        JOIN post_sites ps ON ps.post_id = pt.post_id
+        GROUP BY pt.tag_uuid, ps.site_id
+        ON CONFLICT (tag_uuid, site_id) DO UPDATE
+        SET use_count = EXCLUDED.use_count
+        WHERE tag_sites.use_count <> EXCLUDED.use_count
+        "#
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .rows_affected();
+
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(RecomputeResponse {
+        tags_corrected,
+        site_counts_corrected,
+        site_counts_removed,
+    }))
+}
+
+// A tag's uuid and name, following merges to the tag it went into
+async fn find_tag(
+    conn: &mut PgConnection,
+    site: &SiteIdentity,
+    uuid: Uuid,
+) -> Result<(Uuid, String), AppError> {
+    sqlx::query_as::<_, (Uuid, String)>(
+        "SELECT tag_uuid, tag_name FROM tag_stats WHERE tag_uuid = resolve_tag_uuid($1) FOR UPDATE",
+    )
+    .bind(uuid)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?
+    .ok_or_else(|| AppError::not_found().at_site(site))
+}
+
+// The sites the given tags show on, changing them changes posts on all of them
+async fn tags_sites(
+    conn: &mut PgConnection,
+    site: &SiteIdentity,
+    tags: &[Uuid],
+) -> Result<Vec<i32>, AppError> {
+    sqlx::query_scalar!(
+        r#"SELECT DISTINCT site_id AS "site_id!" FROM tag_sites WHERE tag_uuid = ANY($1)"#,
+        tags
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))
+}
+
+// Editors can change tags, but only where every post using them is theirs to edit
+fn require_tag_editor(actor: &Actor, site_ids: &[i32]) -> Result<(), AppError> {
+    if site_ids.is_empty() {
+        actor.require(Role::Editor)
+    } else {
+        actor.require_on(site_ids, Role::Editor)
+    }
+}
+
+async fn admin_tag(
+    conn: &mut PgConnection,
+    site: &SiteIdentity,
+    popularity: &PopularityConfig,
+    uuid: Uuid,
+) -> Result<Tag, AppError> {
+    let query = format!(
+        "SELECT {}, {} FROM tag_stats WHERE tag_uuid = $1",
+        ADMIN_TAG_COLUMNS,
+        popularity_column(popularity, "NULL")
+    );
+    sqlx::query_as::<_, Tag>(&query)
+        .bind(uuid)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| AppError::from(e).at_site(site))
+}
+
+// Refuse a name that's already taken, by another tag or as another tag's alias
+async fn check_name_free(
+    conn: &mut PgConnection,
+    site: &SiteIdentity,
+    field: &str,
+    name: &str,
+    tag_uuid: Uuid,
+) -> Result<(), AppError> {
+    if name.trim().is_empty() {
+        return Err(AppError::bad_request()
+            .with_message("Invalid tag")
+            .with_fields(vec![FieldError::new(field, "Can't be empty")])
+            .at_site(site));
+    }
+
+    let owner = sqlx::query_scalar!(
+        r#"
+        SELECT tag_uuid AS "tag_uuid!" FROM tag_stats WHERE tag_name = $1
+        UNION ALL
+        SELECT tag_uuid FROM tag_aliases WHERE alias = $1
+        "#,
+        name
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?;
+
+    match owner {
+        Some(owner) if owner != tag_uuid => Err(AppError::conflict()
+            .with_message(format!(
+                "\"{}\" is already used by another tag, merge the tags instead",
+                name
+            ))
+            .at_site(site)),
+        _ => Ok(()),
+    }
+}
+
+// Posts show the new tag names, so their caches and ETags need to move on
+async fn touch_tagged_posts(
+    conn: &mut PgConnection,
+    site: &SiteIdentity,
+    tag_uuid: Uuid,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        "UPDATE posts SET updated_at = NOW() WHERE id IN (SELECT post_id FROM post_tags WHERE tag_uuid = $1)",
+        tag_uuid
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct RenameTagRequest {
+    pub name: String,
+}
+
+/// Rename a tag on every post at once. The old name stays on as an alias.
+pub async fn rename_tag(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+    Json(payload): Json<RenameTagRequest>,
+) -> Result<Json<Tag>, AppError> {
+    let name = payload.name.trim();
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (tag_uuid, old_name) = find_tag(&mut tx, &site, uuid).await?;
+    let site_ids = tags_sites(&mut tx, &site, &[tag_uuid]).await?;
+    require_tag_editor(&actor, &site_ids)?;
+
+    if name != old_name {
+        check_name_free(&mut tx, &site, "name", name, tag_uuid).await?;
+
+        sqlx::query!("DELETE FROM tag_aliases WHERE alias = $1", name)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?;
+
+        sqlx::query!(
+            "UPDATE tag_stats SET tag_name = $2 WHERE tag_uuid = $1",
+            tag_uuid,
+            name
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+        sqlx::query!(
+            "INSERT INTO tag_aliases (alias, tag_uuid) VALUES ($1, $2) ON CONFLICT (alias) DO NOTHING",
+            old_name,
+            tag_uuid
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+        touch_tagged_posts(&mut tx, &site, tag_uuid).await?;
+        webhooks::enqueue_tags(&mut tx, &site_ids, &[name.to_string()])
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?;
+    }
+
+    let tag = admin_tag(&mut tx, &site, &config.popularity, tag_uuid).await?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(tag))
+}
+
+#[derive(Deserialize)]
+pub struct MergeTagRequest {
+    /// The tag to keep
+    pub into: Uuid,
+}
+
+/// Fold a tag into another. Its posts, selections, aliases and children move over,
+/// and its name and uuid keep resolving to the tag it went into.
+pub async fn merge_tag(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+    Json(payload): Json<MergeTagRequest>,
+) -> Result<Json<Tag>, AppError> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (source, source_name) = find_tag(&mut tx, &site, uuid).await?;
+    let (target, target_name) = find_tag(&mut tx, &site, payload.into).await?;
+    if source == target {
+        return Err(AppError::bad_request()
+            .with_message("Invalid merge")
+            .with_fields(vec![FieldError::new(
+                "into",
+                "Can't merge a tag into itself",
+            )])
+            .at_site(&site));
+    }
+
+    let site_ids = tags_sites(&mut tx, &site, &[source, target]).await?;
+    require_tag_editor(&actor, &site_ids)?;
+
+    touch_tagged_posts(&mut tx, &site, source).await?;
+
+    // Posts that had both keep the one tag, the triggers sort out the counts
+    sqlx::query!(
+        r#"
+        INSERT INTO post_tags (post_id, tag_uuid, position)
+        SELECT post_id, $2, position FROM post_tags WHERE tag_uuid = $1
+        ON CONFLICT (post_id, tag_uuid) DO NOTHING
+        "#,
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!("DELETE FROM post_tags WHERE tag_uuid = $1", source)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // A target that sat under the source takes the source's place
+    sqlx::query!(
+        r#"
+        UPDATE tag_stats
+        SET
+            selected_count = selected_count + (SELECT selected_count FROM tag_stats WHERE tag_uuid = $1),
+            parent_uuid = CASE
+                WHEN parent_uuid = $1 THEN (SELECT parent_uuid FROM tag_stats WHERE tag_uuid = $1)
+                ELSE parent_uuid
+            END
+        WHERE tag_uuid = $2
+        "#,
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "UPDATE tag_stats SET parent_uuid = $2 WHERE parent_uuid = $1",
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "UPDATE tag_aliases SET tag_uuid = $2 WHERE tag_uuid = $1",
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "INSERT INTO tag_aliases (alias, tag_uuid) VALUES ($1, $2) ON CONFLICT (alias) DO UPDATE SET tag_uuid = $2",
+        source_name,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Sites that only described the source describe the target with it
+    sqlx::query!(
+        r#"
+        UPDATE tag_details d SET tag_uuid = $2
+        WHERE d.tag_uuid = $1
+        AND NOT EXISTS (SELECT 1 FROM tag_details WHERE tag_uuid = $2 AND site_id = d.site_id)
+        "#,
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Selections are counted by the hour, so the source's add onto the target's. Today's
+    // clients go with the source, so each can count once more for the target.
+    sqlx::query!(
+        r#"
+        INSERT INTO tag_selections (tag_uuid, site_id, bucket, selections)
+        SELECT $2, site_id, bucket, selections FROM tag_selections WHERE tag_uuid = $1
+        ON CONFLICT (tag_uuid, site_id, bucket) DO UPDATE
+        SET selections = tag_selections.selections + EXCLUDED.selections
+        "#,
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    // Earlier merges into the source now point at the target too
+    sqlx::query!(
+        "UPDATE tag_redirects SET tag_uuid = $2 WHERE tag_uuid = $1",
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "INSERT INTO tag_redirects (old_uuid, tag_uuid) VALUES ($1, $2) ON CONFLICT (old_uuid) DO UPDATE SET tag_uuid = $2",
+        source,
+        target
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!("DELETE FROM tag_stats WHERE tag_uuid = $1", source)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    webhooks::enqueue_tags(&mut tx, &site_ids, &[target_name])
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let tag = admin_tag(&mut tx, &site, &config.popularity, target).await?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(tag))
+}
+
+#[derive(Deserialize)]
+pub struct SetParentRequest {
+    /// Null to make the tag top level
+    pub parent_uuid: Option<Uuid>,
+}
+
+/// Put a tag under a broader one, or back at the top level
+pub async fn set_tag_parent(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+    Json(payload): Json<SetParentRequest>,
+) -> Result<Json<Tag>, AppError> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (tag_uuid, tag_name) = find_tag(&mut tx, &site, uuid).await?;
+    let site_ids = tags_sites(&mut tx, &site, &[tag_uuid]).await?;
+    require_tag_editor(&actor, &site_ids)?;
+
+    let parent = match payload.parent_uuid {
+        Some(parent) => {
+            let (parent, _) = find_tag(&mut tx, &site, parent).await?;
+
+            // The tag can't end up under itself, however far up
+            let makes_loop = sqlx::query_scalar!(
+                r#"
+                WITH RECURSIVE ancestors AS (
+                    SELECT $1::UUID AS tag_uuid
+                    UNION
+                    SELECT t.parent_uuid FROM tag_stats t
+                    JOIN ancestors a ON a.tag_uuid = t.tag_uuid
+                    WHERE t.parent_uuid IS NOT NULL
+                )
+                SELECT EXISTS (SELECT 1 FROM ancestors WHERE tag_uuid = $2) AS "makes_loop!"
+                "#,
+                parent,
+                tag_uuid
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| AppError::from(e).at_site(&site))?;
+
+            if makes_loop {
+                return Err(AppError::bad_request()
+                    .with_message("Invalid parent")
+                    .with_fields(vec![FieldError::new(
+                        "parent_uuid",
+                        "Can't be the tag itself or one of the tags under it",
+                    )])
+                    .at_site(&site));
+            }
+            Some(parent)
+        }
+        None => None,
+    };
+
+    sqlx::query!(
+        "UPDATE tag_stats SET parent_uuid = $2 WHERE tag_uuid = $1",
+        tag_uuid,
+        parent
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    webhooks::enqueue_tags(&mut tx, &site_ids, &[tag_name])
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let tag = admin_tag(&mut tx, &site, &config.popularity, tag_uuid).await?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(tag))
+}
+
+#[derive(Deserialize)]
+pub struct AddAliasRequest {
+    pub alias: String,
+}
+
+/// Make another name resolve to a tag
+pub async fn add_tag_alias(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+    Json(payload): Json<AddAliasRequest>,
+) -> Result<(StatusCode, Json<Tag>), AppError> {
+    let alias = payload.alias.trim();
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (tag_uuid, tag_name) = find_tag(&mut tx, &site, uuid).await?;
+    let site_ids = tags_sites(&mut tx, &site, &[tag_uuid]).await?;
+    require_tag_editor(&actor, &site_ids)?;
+
+    if alias == tag_name {
+        return Err(AppError::bad_request()
+            .with_message("Invalid alias")
+            .with_fields(vec![FieldError::new("alias", "Is already the tag's name")])
+            .at_site(&site));
+    }
+    check_name_free(&mut tx, &site, "alias", alias, tag_uuid).await?;
+
+    sqlx::query!(
+        "INSERT INTO tag_aliases (alias, tag_uuid) VALUES ($1, $2) ON CONFLICT (alias) DO NOTHING",
+        alias,
+        tag_uuid
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let tag = admin_tag(&mut tx, &site, &config.popularity, tag_uuid).await?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok((StatusCode::CREATED, Json(tag)))
+}
+
+pub async fn delete_tag_alias(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path((uuid, alias)): Path<(Uuid, String)>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let (tag_uuid, _) = find_tag(&mut tx, &site, uuid).await?;
+    let site_ids = tags_sites(&mut tx, &site, &[tag_uuid]).await?;
+    require_tag_editor(&actor, &site_ids)?;
+
+    let result = sqlx::query!(
+        "DELETE FROM tag_aliases WHERE alias = $1 AND tag_uuid = $2",
+        alias,
+        tag_uuid
+    )
+    .execute(&mut *tx)
     .await
     .map_err(|e| AppError::from(e).at_site(&site))?;
 
     if result.rows_affected() == 0 {
         return Err(AppError::not_found().at_site(&site));
     }
+
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::{related::related_tags, search::suggest};
+    use crate::testing;
+    use axum::{http::Uri, response::IntoResponse};
+
+    fn query<T: serde::de::DeserializeOwned>(uri: &str) -> Result<Query<T>, QueryRejection> {
+        Query::try_from_uri(&uri.parse::<Uri>().unwrap())
+    }
+
+    #[tokio::test]
+    async fn tags_only_on_drafts_stay_hidden() {
+        let pool = testing::pool().await;
+        let config = testing::config();
+        let site = testing::create_site(&pool).await;
+
+        let hidden = testing::unique("hidden");
+        let shown = testing::unique("shown");
+        testing::create_post(&pool, &site, "draft", &[&hidden, &shown]).await;
+        testing::create_post(&pool, &site, "published", &[&shown]).await;
+        let hidden_uuid = testing::tag_uuid(&pool, &hidden).await;
+        let shown_uuid = testing::tag_uuid(&pool, &shown).await;
+
+        let suggested = |name: &str| {
+            let (pool, config, site) = (pool.clone(), config.clone(), site.clone());
+            let uri = format!("/api/search/suggest?search={}", name);
+            async move {
+                let Ok(Json(suggestions)) =
+                    suggest(State(pool), State(config), site, query(&uri)).await
+                else {
+                    panic!("suggest failed");
+                };
+                suggestions.into_iter().map(|s| s.uuid).collect::<Vec<_>>()
+            }
+        };
+        assert!(!suggested(&hidden).await.contains(&hidden_uuid));
+        assert!(suggested(&shown).await.contains(&shown_uuid));
+
+        let related = |uuid: Uuid| {
+            let (pool, config, site) = (pool.clone(), config.clone(), site.clone());
+            async move {
+                related_tags(
+                    State(pool),
+                    State(config),
+                    site,
+                    Path(uuid),
+                    query("/api/tags/related"),
+                )
+                .await
+                .into_response()
+                .status()
+            }
+        };
+        assert_eq!(related(hidden_uuid).await, StatusCode::NOT_FOUND);
+        assert_eq!(related(shown_uuid).await, StatusCode::OK);
+
+        let select = |uuid: Uuid| {
+            let (pool, site) = (pool.clone(), site.clone());
+            let client = Client {
+                addr: "192.0.2.1".to_string(),
+            };
+            async move {
+                increment_tag_selection(State(pool), site, client, Path(uuid.to_string()))
+                    .await
+                    .into_response()
+                    .status()
+            }
+        };
+        assert_eq!(select(hidden_uuid).await, StatusCode::NOT_FOUND);
+        assert_eq!(select(shown_uuid).await, StatusCode::NO_CONTENT);
+
+        let page = get_tag(
+            State(pool.clone()),
+            State(config.clone()),
+            site.clone(),
+            OriginalUri(format!("/api/tags/{}", hidden_uuid).parse().unwrap()),
+            Path(hidden_uuid),
+            query("/api/tags"),
+        )
+        .await
+        .into_response();
+        assert_eq!(page.status(), StatusCode::NOT_FOUND);
+
+        testing::delete_site(&pool, &site).await;
+    }
+}
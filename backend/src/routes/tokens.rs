@@ -0,0 +1,185 @@
+use crate::{
+    auth::{Capability, Role, generate_token, hash_token},
+    error::AppError,
+    extractors::{Actor, SiteIdentity},
+    visibility::Visibility,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct TokenResponse {
+    pub uuid: Uuid,
+    pub name: String,
+    pub site_mask: i32,
+    pub sites: Vec<i32>,
+    pub capability: Capability,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub author_uuid: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedTokenResponse {
+    #[serde(flatten)]
+    pub details: TokenResponse,
+    /// The raw token, this is the only time it is ever returned
+    pub token: String,
+}
+
+// A TokenResponse, with the legacy mask worked out from the token's sites
+const TOKEN_SELECT: &str = "
+    SELECT
+        uuid, name, capability, created_at, last_used_at, revoked_at, author_uuid, expires_at,
+        COALESCE((
+            SELECT BIT_OR(s.site_mask_bit) FROM api_token_sites x
+            JOIN sites s ON s.id = x.site_id
+            WHERE x.token_id = api_tokens.id
+        ), 0) AS site_mask,
+        ARRAY(SELECT site_id FROM api_token_sites WHERE token_id = api_tokens.id ORDER BY site_id) AS sites
+    FROM api_tokens";
+
+pub async fn get_tokens(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+) -> Result<Json<Vec<TokenResponse>>, AppError> {
+    actor.require(Role::Owner)?;
+
+    let query = format!("{} ORDER BY created_at DESC", TOKEN_SELECT);
+    let tokens = sqlx::query_as::<_, TokenResponse>(&query)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .into_iter()
+        // Only tokens the actor could have made, owning every site they cover
+        .filter(|token| actor.role_on(&token.sites) >= Some(Role::Owner))
+        .collect();
+
+    Ok(Json(tokens))
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub site_mask: Option<i32>,
+    pub sites: Option<Vec<i32>>,
+    pub capability: Capability,
+}
+
+pub async fn create_token(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Json(payload): Json<CreateTokenRequest>,
+) -> Result<Json<CreatedTokenResponse>, AppError> {
+    actor.require(Role::Owner)?;
+
+    let visibility = Visibility {
+        visibility_mask: payload.site_mask,
+        sites: payload.sites,
+    };
+    let site_ids = visibility
+        .resolve(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?
+        .ok_or_else(|| {
+            AppError::bad_request()
+                .with_message("Either site_mask or sites is required")
+                .at_site(&site)
+        })?;
+
+    // Can't hand out more than the actor holds on every site the token covers
+    actor.require_on(&site_ids, payload.capability.ceiling())?;
+
+    let raw = generate_token();
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let token_id: i32 = sqlx::query_scalar(
+        "INSERT INTO api_tokens (name, token_hash, capability) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(&payload.name)
+    .bind(hash_token(&raw))
+    .bind(payload.capability)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    sqlx::query!(
+        "INSERT INTO api_token_sites (token_id, site_id) SELECT $1, UNNEST($2::INTEGER[])",
+        token_id,
+        &site_ids
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let query = format!("{} WHERE id = $1", TOKEN_SELECT);
+    let details = sqlx::query_as::<_, TokenResponse>(&query)
+        .bind(token_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(CreatedTokenResponse {
+        details,
+        token: raw,
+    }))
+}
+
+pub async fn revoke_token(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+) -> Result<StatusCode, AppError> {
+    actor.require(Role::Owner)?;
+
+    let token_sites = sqlx::query_scalar!(
+        r#"
+        SELECT ARRAY(
+            SELECT site_id FROM api_token_sites WHERE token_id = api_tokens.id
+        ) AS "sites!"
+        FROM api_tokens WHERE uuid = $1
+        "#,
+        uuid
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?
+    .ok_or_else(|| AppError::not_found().at_site(&site))?;
+
+    // Other sites' tokens and sessions are theirs to revoke
+    actor.require_on(&token_sites, Role::Owner)?;
+
+    let result = sqlx::query!(
+        "UPDATE api_tokens SET revoked_at = NOW() WHERE uuid = $1 AND revoked_at IS NULL",
+        uuid
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found().at_site(&site));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
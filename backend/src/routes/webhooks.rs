@@ -0,0 +1,278 @@
+use crate::{
+    auth::{Role, random_secret},
+    config::AppConfig,
+    error::{AppError, FieldError},
+    extractors::{Actor, SiteIdentity},
+    pagination::Listing,
+    params::PaginationParams,
+    webhooks::{self, WebhookEvent},
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct WebhookResponse {
+    pub uuid: Uuid,
+    pub url: String,
+    /// Empty for every event
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedWebhookResponse {
+    #[serde(flatten)]
+    pub details: WebhookResponse,
+    /// The signing secret, this is the only time it is ever returned
+    pub secret: String,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct DeliveryResponse {
+    pub uuid: Uuid,
+    pub event: String,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+pub async fn get_webhooks(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+) -> Result<Json<Vec<WebhookResponse>>, AppError> {
+    actor.require(Role::Owner)?;
+
+    let webhooks = sqlx::query_as::<_, WebhookResponse>(
+        r#"
+        SELECT uuid, url, events, active, created_at
+        FROM webhooks
+        WHERE site_id = $1
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(site.id)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(Json(webhooks))
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Leave out to be sent every event
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+pub async fn create_webhook(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Json(payload): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<CreatedWebhookResponse>), AppError> {
+    actor.require(Role::Owner)?;
+
+    let valid_url = reqwest::Url::parse(&payload.url)
+        .map(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+        .unwrap_or(false);
+    if !valid_url {
+        return Err(AppError::bad_request()
+            .with_message("Invalid webhook")
+            .with_fields(vec![FieldError::new("url", "Must be an http or https URL")])
+            .at_site(&site));
+    }
+    // Hostnames are checked again each time they're sent to
+    if let Err(e) = webhooks::check_target(&payload.url) {
+        return Err(AppError::bad_request()
+            .with_message("Invalid webhook")
+            .with_fields(vec![FieldError::new("url", e)])
+            .at_site(&site));
+    }
+
+    let mut events: Vec<&str> = payload.events.iter().map(|e| e.as_str()).collect();
+    events.sort_unstable();
+    events.dedup();
+
+    let secret = random_secret();
+    let details = sqlx::query_as::<_, WebhookResponse>(
+        r#"
+        INSERT INTO webhooks (site_id, url, secret, events)
+        VALUES ($1, $2, $3, $4)
+        RETURNING uuid, url, events, active, created_at
+        "#,
+    )
+    .bind(site.id)
+    .bind(&payload.url)
+    .bind(&secret)
+    .bind(&events)
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedWebhookResponse { details, secret }),
+    ))
+}
+
+pub async fn delete_webhook(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+) -> Result<StatusCode, AppError> {
+    actor.require(Role::Owner)?;
+
+    let result = sqlx::query!(
+        "DELETE FROM webhooks WHERE uuid = $1 AND site_id = $2",
+        uuid,
+        site.id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found().at_site(&site));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The delivery log for a webhook, newest first
+pub async fn get_deliveries(
+    State(pool): State<PgPool>,
+    State(config): State<AppConfig>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path(uuid): Path<Uuid>,
+    Query(params): Query<PaginationParams>,
+) -> Result<Listing<DeliveryResponse>, AppError> {
+    actor.require(Role::Owner)?;
+
+    let bounds = params
+        .bounds(
+            config.pagination.default_limit,
+            config.pagination.max_limit("webhook_deliveries"),
+        )
+        .map_err(|e| e.at_site(&site))?;
+
+    let webhook_id = webhook_id(&pool, &site, uuid).await?;
+
+    let deliveries = sqlx::query_as::<_, DeliveryResponse>(
+        r#"
+        SELECT
+            uuid,
+            event,
+            payload,
+            status,
+            attempts,
+            next_attempt_at,
+            last_response_status,
+            last_error,
+            created_at,
+            delivered_at
+        FROM webhook_deliveries
+        WHERE webhook_id = $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT $2 OFFSET $3
+        "#,
+    )
+    .bind(webhook_id)
+    .bind(bounds.limit)
+    .bind(bounds.offset)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let total = if bounds.with_total {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM webhook_deliveries WHERE webhook_id = $1"#,
+            webhook_id
+        )
+        .fetch_one(&pool)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Listing::Plain {
+        items: deliveries,
+        total,
+    })
+}
+
+/// Send a delivery again as soon as possible, whatever happened to it before
+pub async fn retry_delivery(
+    State(pool): State<PgPool>,
+    site: SiteIdentity,
+    actor: Actor,
+    Path((uuid, delivery)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, AppError> {
+    actor.require(Role::Owner)?;
+
+    let webhook_id = webhook_id(&pool, &site, uuid).await?;
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE webhook_deliveries
+        SET status = 'pending', attempts = 0, next_attempt_at = NOW()
+        WHERE uuid = $1 AND webhook_id = $2
+        "#,
+        delivery,
+        webhook_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::not_found().at_site(&site));
+    }
+
+    webhooks::requeue(&mut tx, delivery)
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::from(e).at_site(&site))?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Webhooks are only reachable from the site they belong to
+async fn webhook_id(pool: &PgPool, site: &SiteIdentity, uuid: Uuid) -> Result<i32, AppError> {
+    sqlx::query_scalar!(
+        "SELECT id FROM webhooks WHERE uuid = $1 AND site_id = $2",
+        uuid,
+        site.id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| AppError::from(e).at_site(site))?
+    .ok_or_else(|| AppError::not_found().at_site(site))
+}
@@ -0,0 +1,153 @@
+// Setup shared by tests that need the database. Every test makes its own site, posts
+// and tags under unique names, so tests can run side by side on one database.
+use crate::config::AppConfig;
+use crate::extractors::SiteIdentity;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A name no other test will be using
+pub fn unique(prefix: &str) -> String {
+    format!("{}-{}", prefix, Uuid::new_v4().simple())
+}
+
+pub async fn pool() -> PgPool {
+    dotenvy::dotenv().ok();
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    PgPool::connect(&url).await.unwrap()
+}
+
+pub fn config() -> AppConfig {
+    AppConfig {
+        database_url: String::new(),
+        run_migrations: false,
+        server_addr: "127.0.0.1:0".to_string(),
+        allow_debug_headers: false,
+        local_admin: false,
+        trusted_proxy: false,
+        gpg_email: None,
+        oidc: None,
+        require_if_match: false,
+        pagination: Default::default(),
+        jobs: Default::default(),
+        popularity: Default::default(),
+    }
+}
+
+/// A new site that requires auth, as a request to it would see it
+pub async fn create_site(pool: &PgPool) -> SiteIdentity {
+    let domain = format!("{}.example.com", unique("site"));
+    let id = sqlx::query_scalar!(
+        "INSERT INTO sites (domain, requires_auth) VALUES ($1, TRUE) RETURNING id",
+        domain
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    SiteIdentity {
+        id,
+        domain,
+        requires_auth: true,
+        gpg_email: None,
+        highlight_class_prefix: "hl-".to_string(),
+        local_admin: false,
+    }
+}
+
+/// A post on `site` with the given status and tags, returning its id and uuid
+pub async fn create_post(
+    pool: &PgPool,
+    site: &SiteIdentity,
+    status: &str,
+    tags: &[&str],
+) -> (i32, Uuid) {
+    let post = sqlx::query!(
+        r#"
+        INSERT INTO posts (title, slug, content, status, published_at)
+        VALUES ($1, $1, 'Some content', $2, CASE WHEN $2 = 'draft' THEN NULL ELSE NOW() END)
+        RETURNING id, uuid
+        "#,
+        unique("post"),
+        status
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    sqlx::query!(
+        "INSERT INTO post_sites (post_id, site_id) VALUES ($1, $2)",
+        post.id,
+        site.id
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+    sqlx::query!(
+        "INSERT INTO tag_stats (tag_name) SELECT UNNEST($1::TEXT[]) ON CONFLICT (tag_name) DO NOTHING",
+        &tags
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO post_tags (post_id, tag_uuid)
+        SELECT $1, tag_uuid FROM tag_stats WHERE tag_name = ANY($2)
+        "#,
+        post.id,
+        &tags
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    (post.id, post.uuid)
+}
+
+/// The uuid of a tag by name
+pub async fn tag_uuid(pool: &PgPool, name: &str) -> Uuid {
+    sqlx::query_scalar!("SELECT tag_uuid FROM tag_stats WHERE tag_name = $1", name)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+/// Remove a site with its posts, and the tags only they had
+pub async fn delete_site(pool: &PgPool, site: &SiteIdentity) {
+    let tags = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT pt.tag_uuid FROM post_tags pt
+        JOIN post_sites ps ON ps.post_id = pt.post_id
+        WHERE ps.site_id = $1
+        "#,
+        site.id
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    sqlx::query!(
+        "DELETE FROM posts WHERE id IN (SELECT post_id FROM post_sites WHERE site_id = $1)",
+        site.id
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        DELETE FROM tag_stats
+        WHERE tag_uuid = ANY($1)
+        AND tag_uuid NOT IN (SELECT tag_uuid FROM post_tags)
+        "#,
+        &tags
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query!("DELETE FROM sites WHERE id = $1", site.id)
+        .execute(pool)
+        .await
+        .unwrap();
+}
@@ -0,0 +1,103 @@
+use crate::extractors::SiteIdentity;
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// Where a site lives, for absolute links
+pub fn site_url(site: &SiteIdentity) -> String {
+    let scheme = if site.is_local() { "http" } else { "https" };
+    format!("{}://{}", scheme, site.domain)
+}
+
+/// Links to where a site's frontend shows posts, tags and authors, built from the
+/// patterns in its settings
+pub struct SiteUrls {
+    base: String,
+    post_pattern: String,
+    tag_pattern: Option<String>,
+    author_pattern: Option<String>,
+}
+
+impl SiteUrls {
+    pub async fn load(pool: &PgPool, site: &SiteIdentity) -> Result<Self, sqlx::Error> {
+        let patterns = sqlx::query!(
+            "SELECT post_url_pattern, tag_url_pattern, author_url_pattern FROM sites WHERE id = $1",
+            site.id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        // The localhost fallback site might not exist yet
+        let (post_pattern, tag_pattern, author_pattern) = match patterns {
+            Some(p) => (p.post_url_pattern, p.tag_url_pattern, p.author_url_pattern),
+            None => ("/posts/{slug}".to_string(), None, None),
+        };
+
+        Ok(Self {
+            base: site_url(site),
+            post_pattern,
+            tag_pattern,
+            author_pattern,
+        })
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn has_tags(&self) -> bool {
+        self.tag_pattern.is_some()
+    }
+
+    pub fn has_authors(&self) -> bool {
+        self.author_pattern.is_some()
+    }
+
+    /// `{slug}` falls back to the uuid for posts without a slug
+    pub fn post(&self, slug: Option<&str>, uuid: Uuid) -> String {
+        let uuid = uuid.to_string();
+        self.fill(
+            &self.post_pattern,
+            &[("{slug}", slug.unwrap_or(&uuid)), ("{uuid}", &uuid)],
+        )
+    }
+
+    pub fn tag(&self, name: &str, uuid: Uuid) -> Option<String> {
+        let pattern = self.tag_pattern.as_ref()?;
+        Some(self.fill(pattern, &[("{name}", name), ("{uuid}", &uuid.to_string())]))
+    }
+
+    pub fn author(&self, name: &str, uuid: Uuid) -> Option<String> {
+        let pattern = self.author_pattern.as_ref()?;
+        Some(self.fill(pattern, &[("{name}", name), ("{uuid}", &uuid.to_string())]))
+    }
+
+    // Patterns can be paths on the site or full URLs elsewhere
+    fn fill(&self, pattern: &str, values: &[(&str, &str)]) -> String {
+        let mut url = pattern.to_string();
+        for (placeholder, value) in values {
+            url = url.replace(placeholder, &encode_segment(value));
+        }
+        if url.starts_with('/') {
+            format!("{}{}", self.base, url)
+        } else {
+            url
+        }
+    }
+}
+
+// Percent encode everything but unreserved characters, so values stay in one path segment
+fn encode_segment(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,35 @@
+use serde::Deserialize;
+use sqlx::PgPool;
+
+/// Which sites something is shown on. `visibility_mask` is the legacy bitmask of
+/// `site_mask_bit`s and can only reach sites that have a bit, `sites` lists site ids
+/// and works for every site. Both can be given and are combined.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Visibility {
+    pub visibility_mask: Option<i32>,
+    pub sites: Option<Vec<i32>>,
+}
+
+impl Visibility {
+    /// Resolve to site ids, or `None` if neither field was given
+    pub async fn resolve(&self, pool: &PgPool) -> Result<Option<Vec<i32>>, sqlx::Error> {
+        if self.visibility_mask.is_none() && self.sites.is_none() {
+            return Ok(None);
+        }
+
+        let ids = sqlx::query_scalar!(
+            r#"
+            SELECT id AS "id!" FROM sites
+            WHERE (site_mask_bit & $1) <> 0
+            OR id = ANY($2)
+            ORDER BY id
+            "#,
+            self.visibility_mask.unwrap_or(0),
+            self.sites.as_deref().unwrap_or_default()
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Some(ids))
+    }
+}
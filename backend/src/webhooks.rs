@@ -0,0 +1,656 @@
+use crate::jobs::{self, JobKind};
+use crate::models::Post;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use sqlx::{PgConnection, PgPool};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How long a receiver gets to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Retries back off from 30 seconds, doubling up to 6 hours, for about a day and a half in all
+pub const FIRST_RETRY_SECS: i64 = 30;
+pub const MAX_RETRY_SECS: i64 = 6 * 60 * 60;
+pub const MAX_ATTEMPTS: i32 = 12;
+
+// Receiver responses are only kept in the log up to this length
+const MAX_LOGGED_BODY: usize = 500;
+
+/// Something that happened which webhooks can be subscribed to
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "text")]
+pub enum WebhookEvent {
+    #[serde(rename = "post.created")]
+    #[sqlx(rename = "post.created")]
+    PostCreated,
+    #[serde(rename = "post.updated")]
+    #[sqlx(rename = "post.updated")]
+    PostUpdated,
+    #[serde(rename = "post.deleted")]
+    #[sqlx(rename = "post.deleted")]
+    PostDeleted,
+    #[serde(rename = "post.published")]
+    #[sqlx(rename = "post.published")]
+    PostPublished,
+    /// Tags were added to or taken off posts, or the tags themselves changed
+    #[serde(rename = "tag.updated")]
+    #[sqlx(rename = "tag.updated")]
+    TagUpdated,
+    #[serde(rename = "author.created")]
+    #[sqlx(rename = "author.created")]
+    AuthorCreated,
+    #[serde(rename = "author.updated")]
+    #[sqlx(rename = "author.updated")]
+    AuthorUpdated,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PostCreated => "post.created",
+            Self::PostUpdated => "post.updated",
+            Self::PostDeleted => "post.deleted",
+            Self::PostPublished => "post.published",
+            Self::TagUpdated => "tag.updated",
+            Self::AuthorCreated => "author.created",
+            Self::AuthorUpdated => "author.updated",
+        }
+    }
+}
+
+/// Queue an event for every webhook subscribed to it on the given sites, or on every
+/// site for `None`, with a job to send each delivery. Called inside the transaction
+/// making the change, so an event is only ever sent for changes that were saved.
+pub async fn enqueue(
+    conn: &mut PgConnection,
+    site_ids: Option<&[i32]>,
+    event: WebhookEvent,
+    data: Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        WITH deliveries AS (
+            INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT id, $2, $3
+            FROM webhooks
+            WHERE active
+            AND ($1::INTEGER[] IS NULL OR site_id = ANY($1))
+            AND (CARDINALITY(events) = 0 OR $2 = ANY(events))
+            RETURNING uuid
+        )
+        INSERT INTO jobs (kind, payload, max_attempts)
+        SELECT $4, jsonb_build_object('delivery', uuid), $5
+        FROM deliveries
+        "#,
+    )
+    .bind(site_ids)
+    .bind(event.as_str())
+    .bind(data)
+    .bind(JobKind::DeliverWebhook.as_str())
+    .bind(JobKind::DeliverWebhook.max_attempts())
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Queue a delivery to be sent again from its first attempt, e.g. after it failed for good
+pub async fn requeue(conn: &mut PgConnection, delivery: Uuid) -> Result<(), sqlx::Error> {
+    // A job still waiting to send it is replaced rather than left to run alongside
+    sqlx::query!(
+        r#"
+        DELETE FROM jobs
+        WHERE kind = $1
+        AND status IN ('queued', 'dead')
+        AND payload->>'delivery' = $2
+        "#,
+        JobKind::DeliverWebhook.as_str(),
+        delivery.to_string()
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    jobs::enqueue(
+        conn,
+        JobKind::DeliverWebhook,
+        json!({ "delivery": delivery }),
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Queue a post event for the sites the post is on
+pub async fn enqueue_post(
+    conn: &mut PgConnection,
+    post: &Post,
+    event: WebhookEvent,
+) -> Result<(), sqlx::Error> {
+    let site_ids: Vec<i32> =
+        sqlx::query_scalar!("SELECT site_id FROM post_sites WHERE post_id = $1", post.id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    enqueue(conn, Some(&site_ids), event, post_data(post)).await
+}
+
+/// Queue a tag.updated event for tags whose posts changed on the given sites
+pub async fn enqueue_tags(
+    conn: &mut PgConnection,
+    site_ids: &[i32],
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    if tags.is_empty() {
+        return Ok(());
+    }
+
+    let tags = sqlx::query!(
+        r#"
+        SELECT tag_uuid, tag_name, use_count
+        FROM tag_stats
+        WHERE tag_name = ANY($1)
+        ORDER BY tag_name
+        "#,
+        tags
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let data = json!({
+        "tags": tags
+            .iter()
+            .map(|t| json!({ "uuid": t.tag_uuid, "name": t.tag_name, "use_count": t.use_count }))
+            .collect::<Vec<_>>(),
+    });
+    enqueue(conn, Some(site_ids), WebhookEvent::TagUpdated, data).await
+}
+
+/// What receivers are told about a post, enough to know what to rebuild
+pub fn post_data(post: &Post) -> Value {
+    json!({
+        "uuid": post.uuid,
+        "slug": post.slug,
+        "title": post.title,
+        "tags": post.tags,
+        "author_uuid": post.author_uuid,
+        "status": post.status,
+        "published_at": post.published_at,
+        "updated_at": post.updated_at,
+    })
+}
+
+/// The signature receivers check: HMAC-SHA256 over `{timestamp}.{body}`, hex encoded
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// How a delivery attempt went
+pub enum Outcome {
+    Delivered(u16),
+    /// The receiver answered with something other than a 2xx
+    Rejected(u16, String),
+    /// The receiver couldn't be reached
+    Failed(String),
+}
+
+/// Send one signed delivery
+pub async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    delivery: Uuid,
+    event: &str,
+    body: String,
+) -> Outcome {
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(secret, timestamp, &body);
+
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Ametrine-Event", event)
+        .header("X-Ametrine-Delivery", delivery.to_string())
+        .header("X-Ametrine-Timestamp", timestamp.to_string())
+        .header("X-Ametrine-Signature", format!("sha256={}", signature))
+        .body(body)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            Outcome::Delivered(response.status().as_u16())
+        }
+        Ok(response) => {
+            let status = response.status().as_u16();
+            let mut text = response.text().await.unwrap_or_default();
+            if let Some((cut, _)) = text.char_indices().nth(MAX_LOGGED_BODY) {
+                text.truncate(cut);
+            }
+            Outcome::Rejected(status, text)
+        }
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingDelivery {
+    id: i64,
+    event: String,
+    payload: Value,
+    created_at: DateTime<Utc>,
+    url: String,
+    secret: String,
+    site_id: i32,
+}
+
+/// Whether an address is out on the internet, rather than on this machine or a
+/// private network that a webhook could be used to reach into
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_unspecified()
+                    || ip.is_multicast())
+            }
+        },
+    }
+}
+
+/// Refuse a receiver given as an address that isn't public. Hostnames are checked
+/// when they're resolved, addresses given outright never are.
+pub fn check_target(url: &str) -> Result<(), String> {
+    let Some(host) = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+    else {
+        return Err(format!("{} is not a valid URL", url));
+    };
+    match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) if !is_public(ip) => Err(format!("{} is not a public address", ip)),
+        _ => Ok(()),
+    }
+}
+
+// Resolves receivers, leaving out addresses that aren't public. Connections go to
+// what this returns, so a host can't pass a check and then resolve elsewhere.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// Redirects aren't followed, they could lead anywhere the checks don't see
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .expect("the webhook client has no settings that can fail")
+    })
+}
+
+/// Send a delivery for its `attempts`th try, run by a `DeliverWebhook` job. Fails when
+/// the receiver didn't take it, so the job is retried on the webhook backoff.
+pub async fn deliver(
+    pool: &PgPool,
+    delivery: Uuid,
+    attempts: i32,
+    max_attempts: i32,
+) -> anyhow::Result<()> {
+    // Deliveries already sent, or whose webhook was turned off or removed, are left be
+    let pending = sqlx::query_as::<_, PendingDelivery>(
+        r#"
+        SELECT d.id, d.event, d.payload, d.created_at, w.url, w.secret, w.site_id
+        FROM webhook_deliveries d
+        JOIN webhooks w ON w.id = d.webhook_id
+        WHERE d.uuid = $1 AND d.status = 'pending' AND w.active
+        "#,
+    )
+    .bind(delivery)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(pending) = pending else {
+        return Ok(());
+    };
+
+    // The same body every attempt, so receivers can dedupe on the id
+    let body = json!({
+        "id": delivery,
+        "event": pending.event,
+        "site_id": pending.site_id,
+        "created_at": pending.created_at,
+        "data": pending.payload,
+    })
+    .to_string();
+    let outcome = match check_target(&pending.url) {
+        Ok(()) => {
+            send(
+                client(),
+                &pending.url,
+                &pending.secret,
+                delivery,
+                &pending.event,
+                body,
+            )
+            .await
+        }
+        Err(e) => Outcome::Failed(e),
+    };
+
+    let (status, error) = match outcome {
+        Outcome::Delivered(status) => {
+            sqlx::query!(
+                r#"
+                UPDATE webhook_deliveries
+                SET
+                    status = 'delivered',
+                    attempts = $2,
+                    delivered_at = NOW(),
+                    last_response_status = $3,
+                    last_error = NULL
+                WHERE id = $1
+                "#,
+                pending.id,
+                attempts,
+                status as i32
+            )
+            .execute(pool)
+            .await?;
+            return Ok(());
+        }
+        Outcome::Rejected(status, body) => {
+            (Some(status as i32), format!("HTTP {}: {}", status, body))
+        }
+        Outcome::Failed(error) => (None, error),
+    };
+
+    // Out of attempts, the delivery stays in the log as failed
+    sqlx::query!(
+        r#"
+        UPDATE webhook_deliveries
+        SET
+            status = CASE WHEN $2::INTEGER >= $3::INTEGER THEN 'failed' ELSE 'pending' END,
+            attempts = $2,
+            next_attempt_at = NOW() + make_interval(secs => $4),
+            last_response_status = $5,
+            last_error = $6
+        WHERE id = $1
+        "#,
+        pending.id,
+        attempts,
+        max_attempts,
+        JobKind::DeliverWebhook.retry_delay(attempts) as f64,
+        status,
+        error
+    )
+    .execute(pool)
+    .await?;
+
+    anyhow::bail!("Delivery {} was not taken: {}", delivery, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{Router, body::Bytes, extract::State, http::HeaderMap, routing::post};
+    use std::sync::{Arc, Mutex};
+
+    const SECRET: &str = "test-secret";
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    // A local receiver answering every request with `status`
+    async fn receiver(status: u16) -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    move |State(received): State<Received>, headers: HeaderMap, body: Bytes| async move {
+                        received
+                            .lock()
+                            .unwrap()
+                            .push((headers, String::from_utf8_lossy(&body).to_string()));
+                        axum::http::StatusCode::from_u16(status).unwrap()
+                    },
+                ),
+            )
+            .with_state(received.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let (url, received) = receiver(204).await;
+        let delivery = Uuid::new_v4();
+        let body = json!({ "id": delivery, "event": "post.created" }).to_string();
+
+        let outcome = send(
+            &reqwest::Client::new(),
+            &url,
+            SECRET,
+            delivery,
+            "post.created",
+            body.clone(),
+        )
+        .await;
+        assert!(matches!(outcome, Outcome::Delivered(204)));
+
+        let received = received.lock().unwrap();
+        let (headers, received_body) = &received[0];
+        assert_eq!(received_body, &body);
+        assert_eq!(headers["x-ametrine-event"], "post.created");
+        assert_eq!(
+            headers["x-ametrine-delivery"],
+            delivery.to_string().as_str()
+        );
+
+        let timestamp: i64 = headers["x-ametrine-timestamp"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let expected = format!("sha256={}", sign(SECRET, timestamp, &body));
+        assert_eq!(headers["x-ametrine-signature"], expected.as_str());
+        assert_ne!(
+            sign("another-secret", timestamp, &body),
+            sign(SECRET, timestamp, &body)
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_keep_the_response() {
+        let (url, _) = receiver(503).await;
+        let outcome = send(
+            &reqwest::Client::new(),
+            &url,
+            SECRET,
+            Uuid::new_v4(),
+            "post.updated",
+            "{}".to_string(),
+        )
+        .await;
+        assert!(matches!(outcome, Outcome::Rejected(503, _)));
+
+        let outcome = send(
+            &reqwest::Client::new(),
+            "http://127.0.0.1:9/hook",
+            SECRET,
+            Uuid::new_v4(),
+            "post.updated",
+            "{}".to_string(),
+        )
+        .await;
+        assert!(matches!(outcome, Outcome::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn queued_deliveries_are_sent_by_their_jobs() {
+        let pool = testing::pool().await;
+        let site = testing::create_site(&pool).await;
+        let (url, received) = receiver(204).await;
+        // Both reach this machine, one as an address and one through the resolver
+        let by_name = url.replace("127.0.0.1", "localhost");
+        for url in [&url, &by_name] {
+            sqlx::query!(
+                "INSERT INTO webhooks (site_id, url, secret) VALUES ($1, $2, $3)",
+                site.id,
+                url,
+                SECRET
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let mut tx = pool.begin().await.unwrap();
+        enqueue(
+            &mut tx,
+            Some(&[site.id]),
+            WebhookEvent::PostCreated,
+            json!({ "slug": "hello" }),
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let deliveries = sqlx::query!(
+            r#"
+            SELECT d.uuid, j.max_attempts
+            FROM webhook_deliveries d
+            JOIN webhooks w ON w.id = d.webhook_id
+            JOIN jobs j ON j.kind = 'deliver_webhook' AND j.payload->>'delivery' = d.uuid::TEXT
+            WHERE w.site_id = $1
+            "#,
+            site.id
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(deliveries.len(), 2);
+
+        for delivery in &deliveries {
+            assert_eq!(delivery.max_attempts, MAX_ATTEMPTS);
+            let sent = deliver(&pool, delivery.uuid, 1, MAX_ATTEMPTS).await;
+            // Left pending for the job to retry
+            assert!(sent.is_err());
+
+            let row = sqlx::query!(
+                "SELECT status, attempts, last_error FROM webhook_deliveries WHERE uuid = $1",
+                delivery.uuid
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            assert_eq!(row.status, "pending");
+            assert_eq!(row.attempts, 1);
+            assert!(row.last_error.is_some());
+        }
+        assert!(received.lock().unwrap().is_empty());
+
+        // Already delivered, so running the job again does nothing
+        sqlx::query!(
+            "UPDATE webhook_deliveries SET status = 'delivered' WHERE uuid = $1",
+            deliveries[0].uuid
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let again = deliver(&pool, deliveries[0].uuid, 2, MAX_ATTEMPTS).await;
+        assert!(again.is_ok());
+
+        let uuids: Vec<String> = deliveries.iter().map(|d| d.uuid.to_string()).collect();
+        sqlx::query!(
+            "DELETE FROM jobs WHERE kind = 'deliver_webhook' AND payload->>'delivery' = ANY($1)",
+            &uuids
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        testing::delete_site(&pool, &site).await;
+    }
+
+    #[test]
+    fn only_public_targets_are_sent_to() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://10.1.2.3/hook",
+            "http://192.168.0.10:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(check_target(url).is_err(), "{} was allowed", url);
+        }
+
+        for url in [
+            "https://93.184.215.14/hook",
+            "https://[2606:4700::1]/hook",
+            "https://hooks.example.com/hook",
+        ] {
+            assert!(check_target(url).is_ok(), "{} was refused", url);
+        }
+    }
+
+    #[test]
+    fn retries_back_off() {
+        let delay = |attempts| JobKind::DeliverWebhook.retry_delay(attempts);
+        assert_eq!(delay(1), 30);
+        assert_eq!(delay(2), 60);
+        assert_eq!(delay(5), 480);
+        assert_eq!(delay(MAX_ATTEMPTS), MAX_RETRY_SECS);
+    }
+}